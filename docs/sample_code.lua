@@ -31,6 +31,8 @@ function execute_code()
 	lower = global_data.lower
 	upper = global_data.upper
 
+	participant_result.divisor = 0
+
 
 	for i = lower,upper,1
 	do
@@ -41,18 +43,23 @@ function execute_code()
 		if (prime % i == 0 )
 		then
 			participant_result.divisor = i
-
+			break
 		end
 
 	end
 
-	participant_result.divisor = 0
-
 
 	return participant_result
 
 end
 
+function on_result(task_index, result, participant_name)
+
+	-- One divisor is enough, so stop the other participants
+	return result.divisor ~= 0
+
+end
+
 function interpret_results()
 
 
@@ -0,0 +1,60 @@
+use hmac::{Hmac, Mac, NewMac};
+use rand::RngCore;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length in bytes of the random challenges exchanged when a participant registers
+pub const CHALLENGE_LENGTH: usize = 32;
+
+/// Role mixed into responses sent by the host, so that a response cannot be replayed in the other direction
+pub const HOST_ROLE: &[u8] = b"midas host";
+
+/// Role mixed into responses sent by participants
+pub const PARTICIPANT_ROLE: &[u8] = b"midas participant";
+
+/// Creates a new random challenge
+pub fn new_challenge() -> Vec<u8> {
+    let mut challenge = vec![0u8; CHALLENGE_LENGTH];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    challenge
+}
+
+fn mac(secret: &[u8], role: &[u8], challenge: &[u8]) -> HmacSha256 {
+    //HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_varkey(secret).unwrap();
+    mac.update(role);
+    mac.update(challenge);
+    mac
+}
+
+/// Proves knowledge of `secret` by answering the other side's `challenge`
+pub fn respond(secret: &[u8], role: &[u8], challenge: &[u8]) -> Vec<u8> {
+    mac(secret, role, challenge).finalize().into_bytes().to_vec()
+}
+
+/// Checks, in constant time, that `response` is the answer to our `challenge`
+pub fn verify(secret: &[u8], role: &[u8], challenge: &[u8], response: &[u8]) -> bool {
+    mac(secret, role, challenge).verify(response).is_ok()
+}
+
+/// Reads the shared secret from the `--secret` or `--secret-file` options
+pub fn read_secret(secret: Option<&str>, secret_file: Option<&str>) -> Result<Option<Vec<u8>>, String> {
+    let secret = match (secret, secret_file) {
+        (Some(secret), _) => Vec::from(secret.as_bytes()),
+        (None, Some(path)) => match std::fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => return Err(format!("Could not read secret file ({}) - {}", path, e))
+        },
+        (None, None) => return Ok(None)
+    };
+
+    //Trailing newlines are usually left by editors, and are not part of the secret
+    let length = secret.iter().rposition(|byte| *byte != b'\n' && *byte != b'\r').map_or(0, |last| last + 1);
+
+    if length == 0 {
+        return Err(format!("The secret is empty"));
+    }
+
+    Ok(Some(Vec::from(&secret[..length])))
+}
@@ -0,0 +1,106 @@
+// Hosts started with `--announce` send an `Announcement` to a multicast group every second, and
+// participants started with `--discover` listen to the group to find the host's address. The host's
+// IP address is taken from where the announcement came from, so only the port is sent.
+
+use message_io::network::{NetEvent, Network, Transport};
+use serde::{Serialize, Deserialize};
+use crossbeam_channel::unbounded;
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+
+use crate::messages::PROTOCOL_VERSION;
+
+/// Multicast group and port that hosts announce themselves on
+pub const DISCOVERY_ADDRESS: &str = "239.255.77.77:3077";
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Sent by the host to the multicast group
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Announcement {
+    //Kept first so that it is read correctly even if the rest of the struct changes
+    pub protocol_version: u32,
+    pub job: String,
+    pub port: u16,
+}
+
+/// Announces the host, listening on `port`, to the local network until the process exits
+pub fn announce(job: String, port: u16) -> Result<(), String> {
+    let mut network = Network::new(|_: NetEvent<Announcement>| {});
+
+    let group = network.connect(Transport::Udp, DISCOVERY_ADDRESS)
+        .map_err(|e| format!("Can not announce to {} - {}", DISCOVERY_ADDRESS, e))?;
+
+    let announcement = Announcement {
+        protocol_version: PROTOCOL_VERSION,
+        job,
+        port,
+    };
+
+    thread::spawn(move ||
+        loop {
+            network.send(group, announcement.clone());
+
+            thread::sleep(ANNOUNCE_INTERVAL);
+        }
+    );
+
+    Ok(())
+}
+
+/// Waits for a host to announce itself, returning its address. With a job name, hosts running any
+/// other job are ignored
+pub fn discover(job: Option<&str>) -> Result<SocketAddr, String> {
+    let (event_sender, event_receiver) = unbounded();
+
+    let mut network = Network::new(move |net_event: NetEvent<Announcement>| {
+        let _ = event_sender.send(net_event);
+    });
+
+    network.listen(Transport::Udp, DISCOVERY_ADDRESS)
+        .map_err(|e| format!("Can not listen for hosts at {} - {}", DISCOVERY_ADDRESS, e))?;
+
+    //Each host announces itself every second, so only say why a host is ignored the first time
+    let mut ignored = HashSet::new();
+
+    loop {
+        let (endpoint, announcement) = match event_receiver.recv() {
+            Ok(NetEvent::Message(endpoint, announcement)) => (endpoint, announcement),
+            //Announcements from a version whose `Announcement` we cannot read are never a match
+            Ok(NetEvent::DeserializationError(endpoint)) => {
+                if ignored.insert(endpoint.addr()) {
+                    println!("Ignoring host at {} - its announcement could not be read, it may use another protocol version", endpoint.addr());
+                }
+
+                continue;
+            },
+            Ok(_) => continue,
+            Err(e) => return Err(format!("Stopped listening for hosts - {}", e))
+        };
+
+        let address = SocketAddr::new(endpoint.addr().ip(), announcement.port);
+
+        let reason = if announcement.protocol_version != PROTOCOL_VERSION {
+            Some(format!("it uses protocol version {}, we use {}", announcement.protocol_version, PROTOCOL_VERSION))
+        } else if job.map_or(false, |job| job != announcement.job) {
+            Some(format!("it is running job '{}'", announcement.job))
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => {
+                if ignored.insert(address) {
+                    println!("Ignoring host at {} - {}", address, reason);
+                }
+            },
+            None => {
+                println!("Found host running job '{}' at {}", announcement.job, address);
+                return Ok(address);
+            }
+        }
+    }
+}
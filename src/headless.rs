@@ -0,0 +1,137 @@
+
+use crate::messages::{UiEvents, HostEvent, Severity, NodeType};
+
+use crossbeam_channel::{Sender, Receiver, RecvTimeoutError};
+use message_io::network::Endpoint;
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+/// Exit code used when the job finishes and `interpret_results` returns
+pub const EXIT_SUCCESS: i32 = 0;
+
+/// Exit code used when the job fails or is abandoned
+pub const EXIT_JOB_FAILED: i32 = 1;
+
+/// Exit code used when no participants registered before the wait timed out
+pub const EXIT_NO_PARTICIPANTS: i32 = 2;
+
+/// Runs a single job without a terminal, for use in scripts and scheduled jobs. Takes the place of
+/// `ui::Panel` when the host is started with `--headless`.
+pub struct Headless<'a> {
+    command_sender: Sender<HostEvent>,
+
+    message_receiver: Receiver<UiEvents>,
+
+    script_path: & 'a str,
+
+    log: Box<dyn Write>,
+    log_to_stdout: bool,
+
+    //Participant processes, however many threads each one has
+    participants: HashSet<Endpoint>,
+
+    participants_needed: usize, //Start the job once this many participants have registered
+    wait: Option<Duration>, //Or once this much time has passed, if at least one participant has registered
+    started: Instant,
+
+    job_started: bool,
+}
+
+impl<'a> Headless<'a> {
+    /// Creates the runner. Logs are written to `log`, or to stdout if it is `None`
+    pub fn new(command_sender: Sender<HostEvent>, message_receiver: Receiver<UiEvents>, script_path: & 'a str, log: Option<Box<dyn Write>>, participants_needed: usize, wait: Option<Duration>) -> Self {
+        let log_to_stdout = log.is_none();
+
+        Headless {
+            command_sender,
+            message_receiver,
+            script_path,
+            log: log.unwrap_or_else(|| Box::new(std::io::stdout())),
+            log_to_stdout,
+            participants: HashSet::new(),
+            participants_needed,
+            wait,
+            started: Instant::now(),
+            job_started: false,
+        }
+    }
+
+    fn log(& mut self, severity: Severity, node_type: NodeType, message: &str) {
+        writeln!(self.log, "{} {:<8} {:<12} {}", Utc::now().format("%F %X"), severity.to_str(), node_type.to_str(), message).unwrap();
+        self.log.flush().unwrap();
+    }
+
+    fn begin(& mut self) {
+        self.job_started = true;
+        self.command_sender.send(HostEvent::Begin(String::from(self.script_path))).unwrap();
+    }
+
+    // Disconnects the participants and gives back the code the process should exit with
+    fn finish(& mut self, exit_code: i32) -> i32 {
+        self.command_sender.send(HostEvent::RemoveAll).unwrap();
+        exit_code
+    }
+
+    /// Waits for participants, runs the job and returns the exit code once it has finished
+    pub fn run(& mut self) -> i32 {
+        self.log(Severity::Info, NodeType::Host, format!("Waiting for {} participants...", self.participants_needed).as_str());
+
+        loop {
+            match self.message_receiver.recv_timeout(Duration::from_millis(500)) {
+                Ok(event) => match event {
+                    UiEvents::Log(node_type, message, severity) => {
+                        self.log(severity, node_type, message.as_str());
+                    },
+                    UiEvents::MachineRegistered(endpoint, _) => {
+                        self.participants.insert(endpoint);
+                    },
+                    UiEvents::MachineUnregistered(endpoint) => {
+                        self.participants.remove(&endpoint);
+                    },
+                    UiEvents::ParticipantUnregistered(name) => {
+                        self.log(Severity::Info, NodeType::Participant(name), "Unregistered.");
+                    },
+                    UiEvents::InterpretResultsReturn(message) => {
+                        //The result always goes to stdout so that it can be captured, even when logging to a file
+                        if !self.log_to_stdout {
+                            self.log(Severity::Result, NodeType::Host, message.as_str());
+                        }
+
+                        println!("{}", message);
+
+                        return self.finish(EXIT_SUCCESS);
+                    },
+                    UiEvents::JobFailed(message) => {
+                        self.log(Severity::Error, NodeType::Host, message.as_str());
+
+                        return self.finish(EXIT_JOB_FAILED);
+                    },
+                    UiEvents::ChangeStatusTo(_, _, _) | UiEvents::ParticipantProgress(_, _) | UiEvents::ParticipantRegistered(_, _) => {},
+                },
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return EXIT_JOB_FAILED,
+            }
+
+            if !self.job_started {
+                let timed_out = self.wait.map_or(false, |wait| self.started.elapsed() >= wait);
+
+                if self.participants.len() >= self.participants_needed {
+                    self.begin();
+                } else if timed_out {
+                    if self.participants.is_empty() {
+                        self.log(Severity::Error, NodeType::Host, "No participants registered before the timeout.");
+
+                        return self.finish(EXIT_NO_PARTICIPANTS);
+                    }
+
+                    self.log(Severity::Warning, NodeType::Host, format!("Timed out waiting for participants, starting with {}.", self.participants.len()).as_str());
+                    self.begin();
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::messages::{Message, UiEvents, ParticipantStatus, Severity, NodeType};
+use crate::messages::{Message, UiEvents, ParticipantStatus, Severity, NodeType, Worker};
 
 use message_io::network::Endpoint;
 
@@ -6,17 +6,76 @@ use bimap::BiMap;
 
 use message_io::network::{Network, NetEvent, Transport};
 
-use hlua::{Lua, AnyLuaValue, LuaTable, LuaFunctionCallError};
-use std::io::Read;
+use hlua::Lua;
+use std::io::{self, Read};
 
-use crate::lua::SerdeLuaTable;
+use crate::lua;
+use crate::lua::{SerdeLuaTable, SerdeLuaValue};
+use crate::output::{Output, JobReport, TaskReport};
 
 use crate::messages::HostEvent;
+use crate::messages;
+use crate::messages::Registration;
+use crate::auth;
+use crate::tls;
+use crate::tls::ServerTls;
+use crate::signing::SigningKey;
+use crate::limits::Limits;
+use crate::discovery;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
 use crossbeam_channel::{Receiver, Sender};
 
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+use crossbeam_channel::RecvTimeoutError;
+use chrono::{DateTime, Utc};
+use std::path::PathBuf;
+use crate::sandbox;
+
+
+// One round of generate -> execute -> reduce. Each field names a function in the script
+#[derive(Clone, Debug)]
+struct Stage {
+    generate: String,
+    map: String,
+    reduce: String,
+    task_count: Option<i32>,
+}
+
+impl Default for Stage {
+    fn default() -> Self {
+        Stage {
+            generate: String::from("generate_data"),
+            map: String::from("execute_code"),
+            reduce: String::from("interpret_results"),
+            task_count: None,
+        }
+    }
+}
+
+impl Stage {
+    fn from_table(table: &SerdeLuaTable) -> Result<Self, String> {
+        let mut stage = Stage::default();
+
+        for (key, value) in table.iter() {
+            match (key, value) {
+                (SerdeLuaValue::String(key), SerdeLuaValue::String(name)) if key == "generate" => stage.generate = name.clone(),
+                (SerdeLuaValue::String(key), SerdeLuaValue::String(name)) if key == "map" => stage.map = name.clone(),
+                (SerdeLuaValue::String(key), SerdeLuaValue::String(name)) if key == "reduce" => stage.reduce = name.clone(),
+                (SerdeLuaValue::String(key), SerdeLuaValue::Number(count)) if key == "task_count" => stage.task_count = Some(*count as i32),
+                _ => return Err(format!("Invalid entry in stage ({:?} = {:?})", key, value))
+            }
+        }
+
+        Ok(stage)
+    }
+}
 
 pub struct Host<'a> {
-    participants: BiMap<String, Endpoint>,
+    //Connected participant processes, and the worker slots of each. Tasks are given to workers
+    machines: BiMap<String, Endpoint>,
+    participants: BiMap<String, Worker>,
     //event_queue: EventQueue<HostEvent>,
     network: Network,
 
@@ -25,19 +84,147 @@ pub struct Host<'a> {
     command_receiver: Receiver<HostEvent>,
     message_sender: Sender<UiEvents>,
 
-    participants_finished: usize,
-    participants_startedwith: BiMap<String, Endpoint>,
-
+    //Task scheduling for the current job. `task_count` is `None` when the script generates tasks
+    //until `generate_data` returns nil
+    job_running: bool,
+    task_count: Option<usize>,
+    next_task: usize,
+    tasks_exhausted: bool,
+    tasks_finished: usize,
+    running_tasks: HashMap<Worker, usize>,
+
+    //Retrying tasks whose participant disconnected. The input of every running task is kept so
+    //it can be sent again, and each task may be retried at most `max_retries` times
+    job_code: String,
+    job_signature: Option<Vec<u8>>,
+    max_retries: usize,
+    task_inputs: HashMap<usize, SerdeLuaTable>,
+    task_attempts: HashMap<usize, usize>,
+    retry_queue: VecDeque<usize>,
+
+    //Deadlines and speculative execution. A task may run on two participants at once, the first
+    //result wins and the other copy is stopped. Stopped participants are not given new tasks until
+    //they report back
+    task_timeout: Option<Duration>,
+    speculative_factor: Option<f64>,
+    task_started: HashMap<Worker, Instant>,
+    task_durations: Vec<Duration>,
+    finished_tasks: HashSet<usize>,
+    speculated_tasks: HashSet<usize>,
+    cancelled: HashSet<Worker>,
+    paused: HashSet<Worker>,
+
+    //Workers the user has excluded from jobs, they are not given any new tasks
+    excluded: HashSet<Worker>,
+
+    //When each participant was last heard from. Participants silent for longer than
+    //`heartbeat_timeout` are unresponsive, and are not given tasks until they are heard from again
+    last_seen: HashMap<Endpoint, Instant>,
+    unresponsive: HashSet<Endpoint>,
+    heartbeat_timeout: Duration,
+
+    //Token given to each participant when it registered, which it must show to replace its connection
+    sessions: HashMap<Endpoint, Vec<u8>>,
+
+    //Stages of a map/reduce pipeline. Scripts without `stages` have a single stage
+    stages: Vec<Stage>,
+    current_stage: usize,
+
+    //Iterations of a job whose last reduce function asks for another round
+    iteration: usize,
+    max_iterations: Option<usize>,
+
+    //Limits participants apply to each call of the map function
+    limits: Limits,
+
+    //Saving the outcome of each job. The participant and duration of every task in the current
+    //stage are kept for the report
+    output: Option<Output>,
+    job_script: String,
+    job_started: DateTime<Utc>,
+    job_participants: HashSet<String>,
+    task_reports: HashMap<usize, (String, Option<Duration>)>,
+
+    //Directory that `_read_file`, `_write_file` and `_append_file` are confined to
+    output_directory: PathBuf,
+
+    //Shared secret participants must prove they know before registering. Participants that have
+    //answered our challenge are kept here with the challenge we sent them
+    secret: Option<Vec<u8>>,
+    authenticating: HashMap<Endpoint, (Registration, Vec<u8>)>,
+
+    //Real addresses of participants connected through the TLS listener
+    tls_peers: Option<tls::PeerAddresses>,
+
+    //Key that scripts are signed with, so participants can check where they came from
+    signing_key: Option<SigningKey>,
 
     lua: Lua<'a>
 }
 
+// Resolves the addresses to listen at, which may be host names. IPv6 addresses come first, so that
+// an IPv6 wildcard socket is bound before an IPv4 one on the same port
+fn resolve_addresses(addresses: &[String]) -> Result<Vec<SocketAddr>, String> {
+    let mut resolved = Vec::new();
+
+    for address in addresses {
+        let found = address.to_socket_addrs().map_err(|e| format!("Can not resolve {} - {}", address, e))?;
+
+        for address in found {
+            if !resolved.contains(&address) {
+                resolved.push(address);
+            }
+        }
+    }
+
+    if resolved.is_empty() {
+        return Err(String::from("No addresses to listen at"));
+    }
+
+    resolved.sort_by_key(|address| !address.is_ipv6());
+
+    Ok(resolved)
+}
+
+// Calls `listen` for every address, returning the addresses being listened at. On systems where IPv6
+// sockets also accept IPv4 connections, the IPv6 wildcard address already takes the IPv4 wildcard
+// address on the same port, so failing to bind the IPv4 one is not an error
+fn listen_all<F>(addresses: &[SocketAddr], mut listen: F) -> Result<Vec<SocketAddr>, String>
+    where F: FnMut(SocketAddr) -> io::Result<()> {
+
+    let mut listening: Vec<SocketAddr> = Vec::new();
+
+    for &address in addresses {
+        match listen(address) {
+            Ok(()) => listening.push(address),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && address.is_ipv4() && address.ip().is_unspecified() &&
+                listening.iter().any(|other| other.is_ipv6() && other.ip().is_unspecified() && other.port() == address.port()) => {
+                listening.push(address);
+            },
+            Err(e) => return Err(format!("Can not listen at {} - {}", address, e))
+        }
+    }
+
+    Ok(listening)
+}
+
+fn list_addresses(addresses: &[SocketAddr]) -> String {
+    addresses.iter().map(|address| address.to_string()).collect::<Vec<_>>().join(", ")
+}
+
 impl<'a> Host<'a> {
 
     pub fn new(command_receiver: Receiver<HostEvent>,
                command_sender: Sender<HostEvent>,
                message_sender: Sender<UiEvents>,
-               server_address: &str) -> Result<Self, String> {
+               server_addresses: &[String],
+               output: Option<Output>,
+               output_directory: PathBuf,
+               secret: Option<Vec<u8>>,
+               tls: Option<ServerTls>,
+               signing_key: Option<SigningKey>,
+               heartbeat_timeout: Duration,
+               announce: Option<String>) -> Result<Self, String> {
 
         let network_sender = command_sender.clone();
 
@@ -45,82 +232,708 @@ impl<'a> Host<'a> {
 
         let mut lua = Lua::new();
 
+        //The host runs scripts the user chose, so unlike sandboxed participants it keeps `io` and `os`
         lua.openlibs();
 
-        match network.listen(Transport::Tcp, server_address) {
-            Ok(_) => message_sender.send(UiEvents::Log(NodeType::Host, format!("Host running at {}", server_address), Severity::Info)).unwrap(),
-            Err(e) => return Err(format!("Can not listen at {} - {}", server_address, e))
+        if let Err(e) = lua::load_helpers(&mut lua) {
+            return Err(format!("Could not load Lua helpers - {}", e));
+        }
+
+        let addresses = resolve_addresses(server_addresses)?;
+
+        let (listening, tls_peers) = match tls {
+            Some(tls) => {
+                //Participants connect to the TLS listeners, which forward to message-io on the loopback interface
+                let internal_address = match network.listen(Transport::Tcp, "127.0.0.1:0") {
+                    Ok((_, address)) => address,
+                    Err(e) => return Err(format!("Can not listen for TLS connections - {}", e))
+                };
+
+                let error_sender = message_sender.clone();
+
+                let on_error: tls::ErrorHandler = Arc::new(move |peer, e| {
+                    error_sender.send(UiEvents::Log(NodeType::Host, format!("TLS connection from {} failed - {}", peer, e), Severity::Warning)).unwrap();
+                });
+
+                let peers = tls::PeerAddresses::default();
+
+                let listening = listen_all(&addresses, |address| tls.serve(address, internal_address, peers.clone(), on_error.clone()))?;
+
+                message_sender.send(UiEvents::Log(NodeType::Host, format!("Host running at {} with TLS, certificate fingerprint {}", list_addresses(&listening), tls.fingerprint()), Severity::Info)).unwrap();
+
+                (listening, Some(peers))
+            },
+            None => {
+                let listening = listen_all(&addresses, |address| network.listen(Transport::Tcp, address).map(|_| ()))?;
+
+                message_sender.send(UiEvents::Log(NodeType::Host, format!("Host running at {}", list_addresses(&listening)), Severity::Info)).unwrap();
+
+                (listening, None)
+            }
         };
 
+        //Participants started with `--discover` find us through these announcements
+        if let Some(job) = announce {
+            discovery::announce(job.clone(), listening[0].port())?;
+
+            message_sender.send(UiEvents::Log(NodeType::Host, format!("Announcing job '{}' on the local network ({})", job, discovery::DISCOVERY_ADDRESS), Severity::Info)).unwrap();
+        }
+
+        if let Some(key) = &signing_key {
+            message_sender.send(UiEvents::Log(NodeType::Host, format!("Signing scripts with public key {}", key.public_key()), Severity::Info)).unwrap();
+        }
+
         Ok(Host {
+            machines: BiMap::new(),
             participants: BiMap::new(),
             command_receiver,
             network,
-            participants_finished: 0,
-            participants_startedwith: BiMap::new(),
+            job_running: false,
+            task_count: None,
+            next_task: 0,
+            tasks_exhausted: false,
+            tasks_finished: 0,
+            running_tasks: HashMap::new(),
+            job_code: String::new(),
+            job_signature: None,
+            max_retries: 0,
+            task_inputs: HashMap::new(),
+            task_attempts: HashMap::new(),
+            retry_queue: VecDeque::new(),
+            task_timeout: None,
+            speculative_factor: None,
+            task_started: HashMap::new(),
+            task_durations: Vec::new(),
+            finished_tasks: HashSet::new(),
+            speculated_tasks: HashSet::new(),
+            cancelled: HashSet::new(),
+            paused: HashSet::new(),
+            excluded: HashSet::new(),
+            last_seen: HashMap::new(),
+            unresponsive: HashSet::new(),
+            heartbeat_timeout,
+            sessions: HashMap::new(),
+            stages: Vec::new(),
+            current_stage: 0,
+            iteration: 1,
+            max_iterations: None,
+            limits: Limits::default(),
+            output,
+            job_script: String::new(),
+            job_started: Utc::now(),
+            job_participants: HashSet::new(),
+            task_reports: HashMap::new(),
+            output_directory,
+            secret,
+            authenticating: HashMap::new(),
+            tls_peers,
+            signing_key,
             message_sender,
             lua
         })
     }
 
-    fn send_data(& mut self) -> Result<(), String> {
-        //Extract the 'generate data' function from the Lua script.
-        let generate_data_option: Option<hlua::LuaFunction<_>> = self.lua.get("generate_data");
+    // Reads the scheduling options from the script. Scripts that declare `task_count` get exactly
+    // that many tasks, scripts that set `task_queue = true` get tasks until `generate_data` returns
+    // nil and all other scripts get one task per participant.
+    fn read_task_count(& mut self) -> Option<usize> {
+        if let Some(count) = self.stages[self.current_stage].task_count {
+            return Some(count.max(0) as usize);
+        }
 
-        match generate_data_option {
-            Some(mut generate_data) => {
-                let endpoint_count = self.participants.len();
+        if let Some(count) = self.lua.get::<i32, _>("task_count") {
+            return Some(count.max(0) as usize);
+        }
 
+        match self.lua.get::<bool, _>("task_queue") {
+            Some(true) => None,
+            _ => Some(self.participants.len())
+        }
+    }
 
-                //Call generate_data function for each endpoint, and send the resultant data
-                for (i, (_name, endpoint)) in self.participants.iter().enumerate() {
-                    let result_option: Result<LuaTable<_>, _> = generate_data.call_with_args((i as i32, endpoint_count as i32));
+    // Reads the `stages` table from the script
+    fn read_stages(& mut self) -> Result<Vec<Stage>, String> {
+        match lua::evaluate_table(&mut self.lua, "stages") {
+            Ok(Some(mut table)) => {
+                //Stages run in the order of their index
+                let mut stages = Vec::new();
+
+                table.sort_by(|(a, _), (b, _)| match (a, b) {
+                    (SerdeLuaValue::Number(a), SerdeLuaValue::Number(b)) => a.partial_cmp(b).unwrap(),
+                    _ => std::cmp::Ordering::Equal
+                });
+
+                for (key, value) in table.iter() {
+                    match value {
+                        SerdeLuaValue::Table(stage) => stages.push(Stage::from_table(stage)?),
+                        _ => return Err(format!("Stage {:?} must be a table", key))
+                    }
+                }
 
-                    match result_option {
-                        Ok(mut result) => {
-                            let list: SerdeLuaTable = result.iter::<AnyLuaValue, AnyLuaValue>().map(|pair| pair.unwrap()).collect();
+                if stages.is_empty() {
+                    return Err(format!("`stages` must contain at least one stage"));
+                }
 
+                Ok(stages)
+            }
+            Ok(None) => Ok(vec![Stage::default()]),
+            Err(e) => Err(format!("Error in `stages` - {}", e))
+        }
+    }
 
-                            self.network.send(*endpoint, Message::VectorHTP(list));
-                        }
-                        Err(e) => {
+    // Calls `generate_data` for the next task, returning `None` once there are no more tasks
+    fn generate_task(& mut self) -> Result<Option<(usize, SerdeLuaTable)>, String> {
+        if self.tasks_exhausted {
+            return Ok(None);
+        }
 
+        if let Some(count) = self.task_count {
+            if self.next_task >= count {
+                self.tasks_exhausted = true;
+                return Ok(None);
+            }
+        }
 
-                            return match e {
-                                LuaFunctionCallError::LuaError(e) => {
-                                    Err(format!("Error in `generate_data` function - {}", e))
-                                }
-                                LuaFunctionCallError::PushError(e) => {
-                                    Err(format!("Error in `generate_data` function - PushError: {:?}", e))
-                                }
-                            }
+        let count_argument = match self.task_count {
+            Some(count) => count.to_string(),
+            None => String::from("nil")
+        };
 
-                        }
-                    }
+        let generate = self.stages[self.current_stage].generate.clone();
+
+        match lua::evaluate_table(&mut self.lua, format!("{}({}, {}, stage_result)", generate, self.next_task, count_argument).as_str()) {
+            Ok(Some(list)) => {
+                let task = self.next_task;
+                self.next_task += 1;
+                Ok(Some((task, list)))
+            }
+            Ok(None) => {
+                self.tasks_exhausted = true;
+                Ok(None)
+            }
+            Err(e) => {
+                Err(format!("Error in `{}` function - {}", generate, e))
+            }
+        }
+    }
+
+    // Sends the next task to `worker`, returning false if there are no tasks left. Tasks waiting
+    // to be retried are sent before any new tasks are generated
+    fn assign_task(& mut self, worker: Worker) -> Result<bool, String> {
+        let next = match self.retry_queue.pop_front() {
+            Some(task) => Some((task, self.task_inputs[&task].clone())),
+            None => self.generate_task()?
+        };
+
+        match next {
+            Some((task, list)) => {
+                self.task_inputs.insert(task, list);
+
+                self.send_task(worker, task);
 
+                Ok(true)
+            }
+            None => Ok(false)
+        }
+    }
+
+    fn send_task(& mut self, worker: Worker, task: usize) {
+        self.running_tasks.insert(worker, task);
+        self.task_started.insert(worker, Instant::now());
+
+        self.send_to(worker, Message::VectorHTP(self.task_inputs[&task].clone()));
+        self.send_to(worker, Message::Execute(self.stages[self.current_stage].map.clone()));
+    }
 
+    // Sends `message` to a single worker slot of a participant
+    fn send_to(& mut self, worker: Worker, message: Message) {
+        self.network.send(worker.endpoint, Message::Slot(worker.slot, Box::new(message)));
+    }
+
+    // Workers of the participant connected at `endpoint`, with their names, in slot order
+    fn workers_of(& self, endpoint: Endpoint) -> Vec<(String, Worker)> {
+        let mut workers: Vec<(String, Worker)> = self.participants.iter()
+            .filter(|(_, worker)| worker.endpoint == endpoint)
+            .map(|(name, worker)| (name.clone(), *worker))
+            .collect();
+
+        workers.sort_by_key(|(_, worker)| worker.slot);
+
+        workers
+    }
+
+    // Workers that are neither running a task, finishing a cancelled one nor excluded
+    fn idle_participants(& self) -> Vec<Worker> {
+        self.participants.right_values()
+            .filter(|worker| !self.running_tasks.contains_key(*worker) && !self.cancelled.contains(*worker) && !self.excluded.contains(*worker) && !self.unresponsive.contains(&worker.endpoint))
+            .cloned()
+            .collect()
+    }
+
+    // Gives a task to every worker that is not currently running one
+    fn dispatch_idle(& mut self) {
+        for worker in self.idle_participants() {
+            match self.assign_task(worker) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    self.abandon_job(e);
+                    break;
                 }
+            }
+        }
+    }
+
+    fn abandon_job(& mut self, reason: String) {
+        self.message_sender.send(UiEvents::Log(NodeType::Host, reason, Severity::Error)).unwrap();
+        self.message_sender.send(UiEvents::JobFailed(format!("Job abandoned."))).unwrap();
 
-                Ok(())
+        self.job_running = false;
+
+        self.stop_running_tasks();
+    }
+
+    // Sends `Message::Stop` to every participant running a task. They are not given new tasks until
+    // they report back
+    fn stop_running_tasks(& mut self) {
+        let workers: Vec<Worker> = self.running_tasks.drain().map(|(worker, _)| worker).collect();
+
+        for worker in workers {
+            self.send_to(worker, Message::Stop);
+            self.cancelled.insert(worker);
+        }
+
+        self.task_started.clear();
+
+        self.retry_queue.clear();
+    }
+
+    // Ends the current stage with the results collected so far, after the script has reported the
+    // job complete from `on_result` or `on_partial`
+    fn finish_early(& mut self, hook: &str, name: String) {
+        self.message_sender.send(UiEvents::Log(NodeType::Participant(name), format!("`{}` reported the job complete, stopping {} remaining tasks.", hook, self.running_tasks.len()), Severity::Info)).unwrap();
+
+        self.stop_running_tasks();
+
+        self.tasks_exhausted = true;
+
+        self.check_job_complete();
+    }
+
+    // Stops every other participant running `task` once one of them has returned a result
+    fn cancel_duplicates(& mut self, task: usize) {
+        let duplicates: Vec<Worker> = self.running_tasks.iter()
+            .filter(|(_, running)| **running == task)
+            .map(|(worker, _)| *worker)
+            .collect();
+
+        for worker in duplicates {
+            self.running_tasks.remove(&worker);
+            self.task_started.remove(&worker);
+
+            self.send_to(worker, Message::Stop);
+            self.cancelled.insert(worker);
+        }
+    }
+
+    // Called periodically to find tasks that have gone over their deadline, or are taking far longer
+    // than the median task, and start a second copy of them on an idle participant
+    fn check_stragglers(& mut self) {
+        if !self.job_running || (self.task_timeout.is_none() && self.speculative_factor.is_none()) {
+            return;
+        }
+
+        let median = if self.task_durations.is_empty() {
+            None
+        } else {
+            let mut durations = self.task_durations.clone();
+            durations.sort();
+            Some(durations[durations.len() / 2])
+        };
+
+        let mut stragglers: Vec<(Worker, usize, Duration)> = self.running_tasks.iter()
+            .filter(|(worker, task)| !self.speculated_tasks.contains(*task) && !self.paused.contains(*worker))
+            .map(|(worker, task)| (*worker, *task, self.task_started[worker].elapsed()))
+            .filter(|(_, _, elapsed)| {
+                let overdue = match self.task_timeout {
+                    Some(timeout) => *elapsed > timeout,
+                    None => false
+                };
+
+                let lagging = match (self.speculative_factor, median) {
+                    (Some(factor), Some(median)) => elapsed.as_secs_f64() > median.as_secs_f64() * factor,
+                    _ => false
+                };
+
+                overdue || lagging
+            })
+            .collect();
+
+        //Longest running tasks get the first idle participants
+        stragglers.sort_by(|a, b| b.2.cmp(&a.2));
+
+        let mut idle = self.idle_participants();
+
+        for (worker, task, elapsed) in stragglers {
+            let backup = match idle.pop() {
+                Some(backup) => backup,
+                None => break
+            };
+
+            let name = self.participants.get_by_right(&worker).unwrap().clone();
+            let backup_name = self.participants.get_by_right(&backup).unwrap().clone();
+
+            self.message_sender.send(UiEvents::Log(NodeType::Participant(name), format!("Task {} has been running for {:.1}s, also running it on '{}'.", task, elapsed.as_secs_f64(), backup_name), Severity::Warning)).unwrap();
+
+            self.speculated_tasks.insert(task);
+
+            self.send_task(backup, task);
+        }
+    }
+
+    // Marks participants we have not heard from within the heartbeat timeout as unresponsive, and
+    // gives the tasks they were running to someone else
+    fn check_liveness(& mut self) {
+        let timeout = self.heartbeat_timeout;
+
+        let silent: Vec<Endpoint> = self.last_seen.iter()
+            .filter(|(endpoint, seen)| seen.elapsed() > timeout && !self.unresponsive.contains(*endpoint))
+            .map(|(endpoint, _)| *endpoint)
+            .collect();
+
+        for endpoint in silent {
+            let machine = self.machines.get_by_right(&endpoint).unwrap().clone();
+
+            self.unresponsive.insert(endpoint);
+
+            self.message_sender.send(UiEvents::Log(NodeType::Participant(machine), format!("Nothing heard from participant for {:.1}s, marking it unresponsive.", timeout.as_secs_f64()), Severity::Warning)).unwrap();
+
+            for (name, worker) in self.workers_of(endpoint) {
+                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Unresponsive, worker, name.clone())).unwrap();
+
+                if self.job_running && self.running_tasks.contains_key(&worker) {
+                    //Should it come back, its result is ignored and it is stopped like a duplicate
+                    self.send_to(worker, Message::Stop);
+                    self.cancelled.insert(worker);
+                    self.paused.remove(&worker);
+
+                    self.retry_task(worker, name, "Participant unresponsive");
+                }
             }
-            None => {
-                Err(format!("`generate_data` function does not exist in script."))
+        }
+    }
+
+    // Records that a participant is alive, bringing it back if it was unresponsive
+    fn participant_seen(& mut self, endpoint: Endpoint) {
+        self.last_seen.insert(endpoint, Instant::now());
+
+        if self.unresponsive.remove(&endpoint) {
+            let machine = self.machines.get_by_right(&endpoint).unwrap().clone();
+
+            for (name, worker) in self.workers_of(endpoint) {
+                let status = if self.cancelled.contains(&worker) || self.running_tasks.contains_key(&worker) {
+                    ParticipantStatus::Calculating
+                } else {
+                    ParticipantStatus::Idle
+                };
+
+                self.message_sender.send(UiEvents::ChangeStatusTo(status, worker, name)).unwrap();
+            }
+
+            self.message_sender.send(UiEvents::Log(NodeType::Participant(machine), format!("Participant is responding again."), Severity::Info)).unwrap();
+
+            if self.job_running {
+                self.dispatch_idle();
+                self.check_job_complete();
+            }
+        }
+    }
+
+    // Called when a worker leaves, so that the task it was running can be given to another
+    fn participant_lost(& mut self, worker: Worker, name: String) {
+        if !self.job_running {
+            return;
+        }
+
+        self.cancelled.remove(&worker);
+        self.paused.remove(&worker);
+        self.excluded.remove(&worker);
+
+        self.retry_task(worker, name, "Participant lost");
+    }
+
+    // Queues the task running on `worker` to be run again, or abandons the job if the task has
+    // failed more than `max_retries` times
+    fn retry_task(& mut self, worker: Worker, name: String, reason: &str) {
+        self.task_started.remove(&worker);
+
+        if let Some(task) = self.running_tasks.remove(&worker) {
+            //Nothing is lost if another participant is running a copy of the task
+            if self.running_tasks.values().any(|running| *running == task) {
+                return;
+            }
+
+            let attempts = {
+                let attempts = self.task_attempts.entry(task).or_insert(0);
+                *attempts += 1;
+                *attempts
+            };
+
+            if attempts > self.max_retries {
+                self.abandon_job(format!("Task {} failed {} times, exceeding the retry limit of {}.", task, attempts, self.max_retries));
+                return;
+            }
+
+            self.message_sender.send(UiEvents::Log(NodeType::Participant(name), format!("{} while running task {}, retrying (attempt {} of {}).", reason, task, attempts, self.max_retries), Severity::Warning)).unwrap();
+
+            self.retry_queue.push_back(task);
+
+            self.dispatch_idle();
+
+            if self.job_running && self.running_tasks.is_empty() {
+                self.message_sender.send(UiEvents::Log(NodeType::Host, format!("No participants left to run task {}, waiting for a participant to register.", task), Severity::Warning)).unwrap();
+            }
+        }
+    }
+
+    // Runs the reduce function of the current stage once every task has been generated and none are
+    // running or waiting to be retried, then moves on to the next stage
+    fn check_job_complete(& mut self) {
+        if !self.job_running || !self.running_tasks.is_empty() || !self.retry_queue.is_empty() {
+            return;
+        }
+
+        //A task queue only ends when `generate_data` returns nil, which needs a worker to take the
+        //next task, but a fixed number of tasks have all been handed out once `next_task` reaches it
+        if self.task_count.map_or(false, |count| self.next_task >= count) {
+            self.tasks_exhausted = true;
+        }
+
+        if !self.tasks_exhausted {
+            //Every worker is excluded, unresponsive or gone, so the job waits for one to come back
+            self.message_sender.send(UiEvents::Log(NodeType::Host, format!("No participants are available to run the remaining tasks, waiting for one."), Severity::Warning)).unwrap();
+            return;
+        }
+
+        if self.current_stage + 1 < self.stages.len() {
+            let reduce = self.stages[self.current_stage].reduce.clone();
+
+            //The return value of an intermediate reduce is passed to the next stage's generate function
+            match self.lua.execute::<()>(format!("stage_result = {}()", reduce).as_str()) {
+                Ok(_) => {
+                    self.start_stage(self.current_stage + 1);
+                }
+                Err(e) => {
+                    self.abandon_job(format!("Error in `{}` function - {}", reduce, e));
+                }
+            }
+        } else {
+            self.job_running = false;
+
+            self.interpret_results();
+        }
+    }
+
+    // Resets the per-stage task state and hands out the first tasks of `stage`
+    fn start_stage(& mut self, stage: usize) {
+        self.current_stage = stage;
+
+        self.task_count = self.read_task_count();
+        self.next_task = 0;
+        self.tasks_exhausted = false;
+        self.tasks_finished = 0;
+        self.running_tasks.clear();
+        self.task_inputs.clear();
+        self.task_attempts.clear();
+        self.retry_queue.clear();
+        self.task_durations.clear();
+        self.finished_tasks.clear();
+        self.speculated_tasks.clear();
+        self.task_reports.clear();
+
+        let stage_name = if self.stages.len() > 1 {
+            format!("stage {} of {} ", stage + 1, self.stages.len())
+        } else {
+            String::new()
+        };
+
+        self.message_sender.send(UiEvents::Log(NodeType::Host, match self.task_count {
+            Some(count) => format!("Starting {}with {} tasks on {} participants.", stage_name, count, self.participants.len()),
+            None => format!("Starting {}with task queue on {} participants.", stage_name, self.participants.len()),
+        }, Severity::Starting)).unwrap();
+
+        self.lua.empty_array("results");
+
+        //Give every participant its first task. Workers still stopping the tasks of an earlier stage
+        //get theirs once they report back
+        self.dispatch_idle();
+
+        if self.job_running && self.tasks_exhausted && self.next_task == 0 {
+            let generate = self.stages[stage].generate.clone();
+
+            self.message_sender.send(UiEvents::JobFailed(format!("`{}` did not generate any tasks.", generate))).unwrap();
+            self.job_running = false;
+        }
+    }
+
+    // Calls `hook(task, tmp_table, name)` if the script defines it, returning true if the hook
+    // reported the job complete
+    fn call_hook(& mut self, hook: &str, task: usize, name: &str) -> bool {
+        if self.lua.get::<hlua::LuaFunction<_>, _>(hook).is_none() {
+            return false;
+        }
+
+        self.lua.set("tmp_name", name);
+
+        match self.lua.execute::<bool>(format!("return {}({}, tmp_table, tmp_name) == true", hook, task).as_str()) {
+            Ok(complete) => complete,
+            Err(e) => {
+                self.message_sender.send(UiEvents::Log(NodeType::Participant(String::from(name)), format!("Error in `{}` function - {}", hook, e), Severity::Error)).unwrap();
+                false
             }
         }
+    }
 
+    // Passes a table sent with `_emit` to the script's `on_partial` function, if it has one
+    fn partial_result(& mut self, worker: Worker, data: SerdeLuaTable) {
+        let task = match self.running_tasks.get(&worker) {
+            Some(task) if self.job_running => *task,
+            _ => return
+        };
+
+        let name = self.participants.get_by_right(&worker).unwrap().clone();
+
+        if let Err(e) = lua::set_table(&mut self.lua, "tmp_table", &data) {
+            self.message_sender.send(UiEvents::Log(NodeType::Participant(name), format!("Ignored a partial result of task {} that could not be read - {}", task, e), Severity::Warning)).unwrap();
+            return;
+        }
 
+        if self.call_hook("on_partial", task, name.as_str()) {
+            self.finish_early("on_partial", name);
+        }
+    }
+
+    // Map functions of every stage, the only functions participants are asked to call
+    fn entry_points(& self) -> Vec<String> {
+        let mut entry_points: Vec<String> = self.stages.iter().map(|stage| stage.map.clone()).collect();
+
+        entry_points.sort();
+        entry_points.dedup();
+
+        entry_points
     }
 
-    fn send_code(& mut self, code: String) {
-        for (_name, endpoint) in self.participants.iter() {
-            self.network.send(*endpoint, Message::Code(code.clone()));
+    fn send_code(& mut self) {
+        let entry_points = self.entry_points();
+
+        for (_name, endpoint) in self.machines.iter() {
+            self.network.send(*endpoint, Message::Code(self.job_code.clone(), entry_points.clone(), self.job_signature.clone()));
+            self.network.send(*endpoint, Message::Limits(self.limits));
         }
     }
 
-    fn execute(& mut self) {
-        for (_, endpoint) in self.participants.iter() {
-            self.network.send(*endpoint, Message::Execute);
+    // Calls the reduce function of the last stage. It returns either a string, which ends the job, or a
+    // table such as `{done = false, next = state}` which runs every stage again with `next` passed to
+    // the first generate function, or `{done = true, message = "..."}` which ends the job
+    fn interpret_results(& mut self) {
+        let reduce = self.stages[self.current_stage].reduce.clone();
+
+        if self.lua.get::<hlua::LuaFunction<_>, _>(reduce.as_str()).is_none() {
+            self.message_sender.send(UiEvents::JobFailed(format!("`{}` function does not exist in script.", reduce))).unwrap();
+            return;
         }
+
+        //Keep the raw results in case the reduce function changes them
+        let results = if self.output.is_some() {
+            lua::evaluate_table(&mut self.lua, "results").unwrap_or(None)
+        } else {
+            None
+        };
+
+        // Get return value
+        if let Err(e) = self.lua.execute::<()>(format!("_midas_return = {}()", reduce).as_str()) {
+            self.message_sender.send(UiEvents::JobFailed(format!("Error in `{}` function - {}", reduce, e))).unwrap();
+            return;
+        }
+
+        if self.lua.execute::<bool>("return type(_midas_return) == 'table'").unwrap() {
+            let done = self.lua.execute::<bool>("return _midas_return.done ~= false").unwrap();
+            let message = self.lua.execute::<String>("return tostring(_midas_return.message or '')").unwrap();
+
+            if done {
+                self.job_finished(message, results);
+            } else if self.max_iterations.map_or(false, |max| self.iteration >= max) {
+                self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Stopped after reaching the limit of {} iterations.", self.iteration), Severity::Warning)).unwrap();
+                self.job_finished(message, results);
+            } else {
+                if !message.is_empty() {
+                    self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Iteration {} - {}", self.iteration, message), Severity::Info)).unwrap();
+                }
+
+                self.lua.execute::<()>("stage_result = _midas_return.next").unwrap();
+
+                self.iteration += 1;
+                self.lua.set("iteration", self.iteration as i32);
+
+                self.job_running = true;
+
+                self.start_stage(0);
+            }
+        } else {
+            match self.lua.execute::<String>("return _midas_return") {
+                Ok(return_code) => {
+                    self.job_finished(return_code, results);
+                }
+                Err(_) => {
+                    self.message_sender.send(UiEvents::JobFailed(format!("`{}` function must return a string or a table.", reduce))).unwrap();
+                }
+            }
+        }
+
+        self.lua.execute::<()>("_midas_return = nil").unwrap();
+    }
+
+    // Saves the report of the job, if the host was asked to, and passes the result on to the UI
+    fn job_finished(& mut self, message: String, results: Option<SerdeLuaTable>) {
+        if let Some(output) = self.output.clone() {
+            let results = results.unwrap_or_default();
+
+            let mut tasks: Vec<TaskReport> = self.task_reports.iter()
+                .map(|(task, (participant, duration))| TaskReport {
+                    task: *task,
+                    participant: participant.clone(),
+                    duration: *duration,
+                    result: results.iter()
+                        .find(|(key, _)| *key == SerdeLuaValue::Number((*task + 1) as f64))
+                        .and_then(|(_, value)| match value {
+                            SerdeLuaValue::Table(table) => Some(table.clone()),
+                            _ => None
+                        }),
+                })
+                .collect();
+
+            tasks.sort_by_key(|task| task.task);
+
+            let mut participants: Vec<String> = self.job_participants.iter().cloned().collect();
+            participants.sort();
+
+            let report = JobReport {
+                script: self.job_script.clone(),
+                started: self.job_started,
+                finished: Utc::now(),
+                iterations: self.iteration,
+                participants,
+                result: message.clone(),
+                tasks,
+            };
+
+            match output.write(&report) {
+                Ok(_) => self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Results written to {}", output.path), Severity::Info)).unwrap(),
+                Err(e) => self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Could not write results to {} - {}", output.path, e), Severity::Error)).unwrap(),
+            }
+        }
+
+        self.message_sender.send(UiEvents::InterpretResultsReturn(message)).unwrap();
     }
 
     pub fn start_participants(& mut self, path: &str) {
@@ -142,37 +955,81 @@ impl<'a> Host<'a> {
                             message_sender.send(UiEvents::Log(NodeType::Host, message, Severity::Stdout)).unwrap();
                         }));
 
+                        sandbox::register_read_file(&mut self.lua, self.output_directory.clone()).unwrap();
+
+                        sandbox::register_write_file(&mut self.lua, self.output_directory.clone()).unwrap();
+
                         match self.lua.execute::<()>(source_code.as_str()) {
                             Ok(_) => {
 
-                                self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Starting calculations on {} participants.", self.participants.len()), Severity::Starting)).unwrap();
+                                self.stages = match self.read_stages() {
+                                    Ok(stages) => stages,
+                                    Err(e) => {
+                                        self.message_sender.send(UiEvents::JobFailed(e)).unwrap();
+                                        return;
+                                    }
+                                };
 
-                                self.participants_finished = 0;
+                                //Make sure the host-side functions of every stage exist before anything is sent
+                                let host_functions: Vec<String> = self.stages.iter()
+                                    .flat_map(|stage| vec![stage.generate.clone(), stage.reduce.clone()])
+                                    .collect();
 
-                                self.participants_startedwith = self.participants.clone();
+                                for function in host_functions {
+                                    if self.lua.get::<hlua::LuaFunction<_>, _>(function.as_str()).is_none() {
+                                        self.message_sender.send(UiEvents::JobFailed(format!("`{}` function does not exist in script.", function))).unwrap();
+                                        return;
+                                    }
+                                }
 
-                                match self.send_data() {
-                                    Ok(_) => {
+                                self.max_retries = match self.lua.get::<i32, _>("max_retries") {
+                                    Some(retries) => retries.max(0) as usize,
+                                    None => 3
+                                };
+                                //`math.huge`, or anything else too long to ever be reached, means no deadline
+                                self.task_timeout = self.lua.get::<f64, _>("task_timeout")
+                                    .filter(|seconds| *seconds <= messages::MAX_TIMEOUT)
+                                    .map(|seconds| Duration::from_secs_f64(seconds.max(0.0)));
+                                self.speculative_factor = match self.lua.get::<bool, _>("speculative_execution") {
+                                    Some(true) => Some(self.lua.get::<f64, _>("speculative_factor").unwrap_or(2.0)),
+                                    _ => None
+                                };
 
+                                self.max_iterations = self.lua.get::<i32, _>("max_iterations").map(|max| max.max(1) as usize);
 
-                                        self.send_code(source_code);
+                                self.limits = Limits {
+                                    wall_time: self.lua.get::<f64, _>("max_execution_time").map(|seconds| seconds.max(0.0)),
+                                    instructions: self.lua.get::<f64, _>("max_instructions").map(|count| count.max(0.0) as u64),
+                                    memory: self.lua.get::<f64, _>("max_memory").map(|bytes| bytes.max(0.0) as u64),
+                                };
 
-                                        self.execute();
-                                    }
-                                    Err(e) => {
-                                        self.message_sender.send(UiEvents::Log(NodeType::Host, e, Severity::Error)).unwrap();
+                                self.lua.execute::<()>("stage_result = nil").unwrap();
 
-                                    }
-                                }
+                                self.iteration = 1;
+                                self.lua.set("iteration", self.iteration as i32);
+
+                                self.job_signature = self.signing_key.as_ref().map(|key| key.sign(source_code.as_str(), &self.entry_points()));
+
+                                self.job_code = source_code;
+
+                                self.send_code();
+
+                                self.job_script = String::from(path);
+                                self.job_started = Utc::now();
+                                self.job_participants.clear();
+
+                                self.job_running = true;
+
+                                self.start_stage(0);
                             }
                             Err(e) => {
-                                self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Bad Lua script - {}", e), Severity::Error)).unwrap();
+                                self.message_sender.send(UiEvents::JobFailed(format!("Bad Lua script - {}", e))).unwrap();
 
                             }
                         }
                     }
                     Err(e) => {
-                        self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Error parsing script - {}", e), Severity::Error)).unwrap();
+                        self.message_sender.send(UiEvents::JobFailed(format!("Error parsing script - {}", e))).unwrap();
 
                     }
                 }
@@ -182,7 +1039,7 @@ impl<'a> Host<'a> {
 
             }
             Err(e) => {
-                self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Error opening script - {}", e), Severity::Error)).unwrap();
+                self.message_sender.send(UiEvents::JobFailed(format!("Error opening script - {}", e))).unwrap();
 
             }
         }
@@ -192,134 +1049,378 @@ impl<'a> Host<'a> {
 
     }
 
-    pub fn check_events(& mut self) {
+    // Address of the participant at the other end of `endpoint`, which differs from the endpoint's
+    // own address when it connected through the TLS listener
+    fn peer_address(& self, endpoint: Endpoint) -> SocketAddr {
+        match &self.tls_peers {
+            Some(peers) => peers.lock().unwrap().get(&endpoint.addr()).cloned().unwrap_or_else(|| endpoint.addr()),
+            None => endpoint.addr()
+        }
+    }
 
-        match self.command_receiver.recv(/*Duration::from_micros(0)*/) {
-            Ok(event) => match event {
-                HostEvent::Network(net_event) => match net_event {
-                    NetEvent::Message(endpoint, message) => {
+    // With TLS, message-io listens on the loopback interface for our forwarders, and any other local
+    // process could connect to it and skip the encryption. Such connections are dropped before they
+    // can register, returning true if `endpoint` was one of them
+    fn drop_untunnelled(& mut self, endpoint: Endpoint) -> bool {
+        let tunnelled = match &self.tls_peers {
+            //Registered peers were checked when they first spoke, and their forwarder may have closed since
+            Some(peers) => self.machines.contains_right(&endpoint) || self.authenticating.contains_key(&endpoint) || peers.lock().unwrap().contains_key(&endpoint.addr()),
+            None => true
+        };
 
+        if !tunnelled {
+            self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Dropped connection from {}, which did not come through TLS", endpoint.addr()), Severity::Warning)).unwrap();
 
-                        match message {
-                            Message::Register(name) => {
-                                if self.participants.contains_left(&name) {
-                                    self.message_sender.send(UiEvents::Log(NodeType::Participant(name.clone()), format!("Could not register participant due to name conflict"), Severity::Warning)).unwrap();
-                                    self.network.remove_resource(endpoint.resource_id());
-                                }
-                                else {
-                                    self.participants.insert(name.clone(), endpoint);
-                                    self.message_sender.send(UiEvents::ParticipantRegistered(endpoint, name.clone())).unwrap();
-                                    //self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Idle, endpoint, name)).unwrap();
+            self.network.remove_resource(endpoint.resource_id());
+        }
 
+        !tunnelled
+    }
 
-                                }
-                            },
-                            Message::Unregister => {
-                                {
-                                    let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                    self.message_sender.send(UiEvents::ParticipantUnregistered(endpoint_name.clone())).unwrap();
-                                }
-                                self.participants.remove_by_right(&endpoint);
-                            },
-                            Message::VectorPTH(data) => {
+    // Drops a peer that cannot be registered, telling it why
+    fn reject(& mut self, endpoint: Endpoint, name: String, reason: String) {
+        let node_type = if name.is_empty() { NodeType::Host } else { NodeType::Participant(name) };
 
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
+        self.message_sender.send(UiEvents::Log(node_type, format!("Rejected peer at {} - {}", self.peer_address(endpoint), reason), Severity::Warning)).unwrap();
 
-                                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Idle, endpoint, endpoint_name.clone())).unwrap();
+        self.authenticating.remove(&endpoint);
+        self.network.send(endpoint, Message::Rejected(reason));
+        self.network.remove_resource(endpoint.resource_id());
+    }
 
-                                if self.participants_startedwith != self.participants {
-                                    self.message_sender.send(UiEvents::Log(NodeType::Participant(endpoint_name.clone()), format!("Some participants have disconnected/connected before execution could complete."), Severity::Error)).unwrap();
+    // Forgets a participant whose connection has gone, retrying the tasks its workers were running
+    fn unregister(& mut self, endpoint: Endpoint) {
+        if self.machines.remove_by_right(&endpoint).is_some() {
+            self.last_seen.remove(&endpoint);
+            self.unresponsive.remove(&endpoint);
+            self.sessions.remove(&endpoint);
 
-                                }
-                                else {
-                                    //A participant has finished, so increment the count
-                                    self.participants_finished += 1;
+            for (_, worker) in self.workers_of(endpoint) {
+                self.remove_worker(worker);
+            }
 
-                                    //If this is the first participant, initialise the results variable
-                                    if self.participants_finished == 1 {
-                                        self.lua.empty_array("results");
-                                    }
+            self.message_sender.send(UiEvents::MachineUnregistered(endpoint)).unwrap();
+        }
+    }
 
-                                    {
-                                        //Create temporary global array called 'tmp_table'
-                                        let mut arr = self.lua.empty_array("tmp_table");
+    // Forgets a single worker, which stops when it is killed
+    fn remove_worker(& mut self, worker: Worker) {
+        if let Some((name, _)) = self.participants.remove_by_right(&worker) {
+            self.message_sender.send(UiEvents::ParticipantUnregistered(name.clone())).unwrap();
 
-                                        // Copy data to temporary array
-                                        for (_, value) in data.iter().enumerate() {
-                                            arr.set(value.0.clone(), value.1.clone());
-                                        }
-                                    }
+            self.participant_lost(worker, name);
+        }
+    }
 
-                                    //Move the temporary table to to the global results
-                                    self.lua.execute::<()>(format!("results[{}] = tmp_table", self.participants_finished).as_str()).unwrap();
+    // Closes the connection of a registered participant, without waiting for message-io to report it
+    fn drop_participant(& mut self, endpoint: Endpoint) {
+        self.network.remove_resource(endpoint.resource_id());
+        self.unregister(endpoint);
+    }
 
-                                    // Test to see if all participants have finished
-                                    if self.participants.len() == self.participants_finished {
+    // Adds a participant that has passed the version and authentication checks, along with a worker
+    // for each of its slots
+    fn register(& mut self, endpoint: Endpoint, registration: Registration) {
+        let name = registration.name.clone();
 
-                                        let interpret_results_option: Option<hlua::LuaFunction<_>> = self.lua.get("interpret_results");
+        if let Some(&stale) = self.machines.get_by_left(&name) {
+            //Only the participant we gave the session to may take over the name
+            if registration.session.is_none() || registration.session.as_ref() != self.sessions.get(&stale) {
+                self.reject(endpoint, name.clone(), format!("A participant called '{}' is already registered", name));
+                return;
+            }
 
-                                        match interpret_results_option {
-                                            Some(mut interpret_results) => {
-                                                // Get return value
-                                                match interpret_results.call::<String>() {
-                                                    Ok(return_code) => {
+            //The participant lost its connection before we noticed, so the old one is dead
+            self.message_sender.send(UiEvents::Log(NodeType::Participant(name.clone()), format!("Reconnected, dropping the previous connection"), Severity::Warning)).unwrap();
+            self.drop_participant(stale);
+        }
 
-                                                        self.message_sender.send(UiEvents::InterpretResultsReturn(return_code)).unwrap();
-                                                    }
-                                                    Err(e) => {
-                                                        self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Error in `interpret_results` function - {}", e), Severity::Error)).unwrap();
+        //Workers of a participant with one slot share its name, the rest are numbered
+        let workers: Vec<(String, Worker)> = (0..registration.threads)
+            .map(|slot| {
+                let worker_name = if registration.threads == 1 { name.clone() } else { format!("{}-{:03}", name, slot) };
 
-                                                    }
-                                                }
+                (worker_name, Worker::new(endpoint, slot))
+            })
+            .collect();
 
-                                            }
-                                            None => {
+        if let Some((taken, _)) = workers.iter().find(|(worker_name, _)| self.participants.contains_left(worker_name)) {
+            self.reject(endpoint, name.clone(), format!("A participant called '{}' is already registered", taken));
+            return;
+        }
 
-                                                self.message_sender.send(UiEvents::Log(NodeType::Host, format!("`interpret_results` function does not exist in script."), Severity::Error)).unwrap();
-                                            }
-                                        }
+        self.message_sender.send(UiEvents::Log(NodeType::Participant(name.clone()), format!("Registered from {} (midas {}, {} threads, {}/{}, {})",
+            self.peer_address(endpoint), registration.midas_version, registration.threads, registration.os, registration.arch, registration.lua_version), Severity::Info)).unwrap();
 
+        let session = auth::new_challenge();
 
+        self.network.send(endpoint, Message::Registered(session.clone()));
 
+        self.machines.insert(name.clone(), endpoint);
+        self.last_seen.insert(endpoint, Instant::now());
+        self.sessions.insert(endpoint, session);
+        self.message_sender.send(UiEvents::MachineRegistered(endpoint, name)).unwrap();
 
-                                    }
+        for (worker_name, worker) in workers {
+            self.participants.insert(worker_name.clone(), worker);
+            self.message_sender.send(UiEvents::ParticipantRegistered(worker, worker_name)).unwrap();
+        }
+
+        //Participants that join during a job can pick up remaining tasks
+        if self.job_running {
+            let entry_points = self.entry_points();
+
+            self.network.send(endpoint, Message::Code(self.job_code.clone(), entry_points, self.job_signature.clone()));
+            self.network.send(endpoint, Message::Limits(self.limits));
+
+            self.dispatch_idle();
+            self.check_job_complete();
+        }
+    }
+
+    // Handles a message sent by one worker slot of a registered participant
+    fn worker_message(& mut self, worker: Worker, message: Message) {
+        match message {
+            Message::Unregister => {
+                self.remove_worker(worker);
+            },
+            Message::VectorPTH(data) => {
+
+                let worker_name = self.participants.get_by_right(&worker).unwrap().clone();
+
+                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Idle, worker, worker_name.clone())).unwrap();
+
+                let task = self.running_tasks.remove(&worker);
+                let started = self.task_started.remove(&worker);
+
+                //Stopped workers are free for new tasks once they report back
+                let was_cancelled = self.cancelled.remove(&worker);
+
+                let mut complete = false;
+
+                //Results from an abandoned job, or for a task another worker already finished, are ignored
+                if self.job_running {
+                    if let Some(task) = task {
+                        if !self.finished_tasks.contains(&task) {
+                            //Copy data to a temporary global table called 'tmp_table'. A result Lua cannot
+                            //hold is treated like one that was lost
+                            if let Err(e) = lua::set_table(&mut self.lua, "tmp_table", &data) {
+                                self.running_tasks.insert(worker, task);
+                                self.retry_task(worker, worker_name, format!("Sent a result that could not be read ({})", e).as_str());
+                                self.check_job_complete();
+                                return;
+                            }
+
+                            self.finished_tasks.insert(task);
+                            self.tasks_finished += 1;
+
+                            self.task_inputs.remove(&task);
+
+                            let duration = started.map(|started| started.elapsed());
+
+                            if let Some(duration) = duration {
+                                self.task_durations.push(duration);
+                            }
+
+                            self.job_participants.insert(worker_name.clone());
+                            self.task_reports.insert(task, (worker_name.clone(), duration));
+
+                            self.cancel_duplicates(task);
+
+                            //Move the temporary table to the global results, indexed by task
+                            self.lua.execute::<()>(format!("results[{}] = tmp_table", task + 1).as_str()).unwrap();
+
+                            complete = self.call_hook("on_result", task, worker_name.as_str());
+                        }
+                    }
+
+                    if complete {
+                        self.finish_early("on_result", worker_name);
+                    }
+                    else if task.is_some() || was_cancelled {
+                        //Hand the worker its next task
+                        self.dispatch_idle();
+
+                        // Test to see if all tasks have finished
+                        self.check_job_complete();
+                    }
+                }
+            },
+            Message::Partial(data) => {
+                self.partial_result(worker, data);
+            },
+            Message::Cancelled => {
+                let worker_name = self.participants.get_by_right(&worker).unwrap().clone();
+
+                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Idle, worker, worker_name.clone())).unwrap();
+                self.message_sender.send(UiEvents::Log(NodeType::Participant(worker_name), format!("Task stopped."), Severity::Info)).unwrap();
+
+                //Stopped workers are free for new tasks once they report back
+                if self.cancelled.remove(&worker) && self.job_running {
+                    self.dispatch_idle();
+                    self.check_job_complete();
+                }
+            },
+            Message::ParticipantError(err) => {
+                let worker_name = self.participants.get_by_right(&worker).unwrap().clone();
+                self.message_sender.send(UiEvents::Log(NodeType::Participant(worker_name.clone()), err, Severity::Error)).unwrap();
+
+                //Errors in the script would happen again on any other worker, so the
+                //task is not retried
+                if self.job_running {
+                    if let Some(task) = self.running_tasks.remove(&worker) {
+                        //The worker reports the error and waits for its next task
+                        self.task_started.remove(&worker);
+                        self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Idle, worker, worker_name.clone())).unwrap();
+
+                        self.abandon_job(format!("Task {} failed on participant '{}'.", task, worker_name));
+                    }
+                }
+            },
+            Message::LimitExceeded(limit, value) => {
+                let worker_name = self.participants.get_by_right(&worker).unwrap().clone();
+
+                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Idle, worker, worker_name.clone())).unwrap();
+                self.message_sender.send(UiEvents::Log(NodeType::Participant(worker_name.clone()), format!("Task exceeded the {} limit of {}.", limit, limit.describe(value)), Severity::Error)).unwrap();
+
+                //The participant may have a lower limit than the job asked for, so another
+                //participant could still finish the task
+                if self.job_running && self.running_tasks.contains_key(&worker) {
+                    self.retry_task(worker, worker_name, "Exceeded a limit");
+
+                    self.dispatch_idle();
+                    self.check_job_complete();
+                }
+            },
+            Message::ParticipantWarning(err) => {
+                let worker_name = self.participants.get_by_right(&worker).unwrap();
+                self.message_sender.send(UiEvents::Log(NodeType::Participant(worker_name.clone()), err, Severity::Warning)).unwrap();
+            },
+            Message::Whisper(err) => {
+                let worker_name = self.participants.get_by_right(&worker).unwrap();
+                self.message_sender.send(UiEvents::Log(NodeType::Participant(worker_name.clone()), err, Severity::Info)).unwrap();
+            },
+            Message::Progress(progress) => {
+                let worker_name = self.participants.get_by_right(&worker).unwrap();
+                self.message_sender.send(UiEvents::ParticipantProgress(worker_name.clone(),progress)).unwrap();
+
+            },
+            Message::Paused => {
+                self.paused.insert(worker);
+
+                let worker_name = self.participants.get_by_right(&worker).unwrap();
+                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Paused, worker, worker_name.clone())).unwrap();
+
+            },
+            Message::Executing => {
+                self.paused.remove(&worker);
+
+                let worker_name = self.participants.get_by_right(&worker).unwrap();
+                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Calculating, worker, worker_name.clone())).unwrap();
+
+            },
+            Message::Stdout(output) => {
+                let worker_name = self.participants.get_by_right(&worker).unwrap();
+                self.message_sender.send(UiEvents::Log(NodeType::Participant(worker_name.clone()), output, Severity::Stdout)).unwrap();
+
+            }
+            _ => {
+                self.invalid_message(worker.endpoint, message);
+            }
+        }
+    }
+
+    // Drops a registered participant that sent a message the host never expects from one
+    fn invalid_message(& mut self, endpoint: Endpoint, message: Message) {
+        let machine = self.machines.get_by_right(&endpoint).cloned().unwrap_or_default();
+
+        self.message_sender.send(UiEvents::Log(NodeType::Participant(machine), format!("Invalid message {:?}, disconnecting participant", message), Severity::Error)).unwrap();
+
+        self.drop_participant(endpoint);
+    }
+
+    pub fn check_events(& mut self) {
+
+        match self.command_receiver.recv_timeout(Duration::from_millis(500)) {
+            Ok(event) => match event {
+                HostEvent::Network(net_event) => match net_event {
+                    NetEvent::Message(endpoint, message) => {
+                        if self.drop_untunnelled(endpoint) {
+                            return;
+                        }
+
+                        //Until a participant has registered (and authenticated, if the host has a secret), only
+                        //registration messages are accepted from it
+                        let registering = matches!(message, Message::Register(_) | Message::ChallengeResponse(_));
+
+                        //Heartbeats and messages from slots still running a task can slip ahead of the registration
+                        //of a participant that is reconnecting
+                        if matches!(message, Message::Heartbeat | Message::Slot(_, _)) && !self.machines.contains_right(&endpoint) {
+                            return;
+                        }
+
+                        if !registering && !self.machines.contains_right(&endpoint) {
+                            self.reject(endpoint, String::new(), format!("Received {:?} before registering", message));
+                            return;
+                        }
+
+                        if !registering {
+                            self.participant_seen(endpoint);
+                        }
+
+                        match message {
+                            Message::Register(registration) => {
+                                if let Some(reason) = registration.incompatibility() {
+                                    self.reject(endpoint, registration.name.clone(), reason);
+                                    return;
+                                }
+
+                                match (self.secret.clone(), registration.challenge.clone()) {
+                                    (None, None) => self.register(endpoint, registration),
+                                    (Some(secret), Some(challenge)) => {
+                                        //Answer the participant's challenge and send our own, the participant is
+                                        //registered once it has answered
+                                        let host_challenge = auth::new_challenge();
+                                        let response = auth::respond(&secret, auth::HOST_ROLE, &challenge);
+
+                                        self.network.send(endpoint, Message::Challenge(host_challenge.clone(), response));
+                                        self.authenticating.insert(endpoint, (registration, host_challenge));
+                                    },
+                                    (Some(_), None) => self.reject(endpoint, registration.name.clone(), format!("Participant did not authenticate, the host requires a secret")),
+                                    (None, Some(_)) => self.reject(endpoint, registration.name.clone(), format!("Participant asked to authenticate, but the host has no secret")),
                                 }
                             },
-                            Message::ParticipantError(err) => {
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                self.message_sender.send(UiEvents::Log(NodeType::Participant(endpoint_name.clone()), err, Severity::Error)).unwrap();
-                            },
-                            Message::ParticipantWarning(err) => {
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                self.message_sender.send(UiEvents::Log(NodeType::Participant(endpoint_name.clone()), err, Severity::Warning)).unwrap();
+                            Message::ChallengeResponse(response) => {
+                                if let Some((registration, challenge)) = self.authenticating.remove(&endpoint) {
+                                    if auth::verify(self.secret.as_ref().unwrap(), auth::PARTICIPANT_ROLE, &challenge, &response) {
+                                        self.register(endpoint, registration);
+                                    } else {
+                                        self.reject(endpoint, registration.name.clone(), format!("Participant failed authentication"));
+                                    }
+                                }
                             },
-                            Message::Whisper(err) => {
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                self.message_sender.send(UiEvents::Log(NodeType::Participant(endpoint_name.clone()), err, Severity::Info)).unwrap();
+                            Message::Unregister => {
+                                self.unregister(endpoint);
                             },
-                            Message::Progress(progress) => {
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                self.message_sender.send(UiEvents::ParticipantProgress(endpoint_name.clone(),progress)).unwrap();
+                            Message::Slot(slot, message) => {
+                                //Workers that have stopped may still have messages on the way
+                                let worker = Worker::new(endpoint, slot);
 
+                                if self.participants.contains_right(&worker) {
+                                    self.worker_message(worker, *message);
+                                }
                             },
-                            Message::Paused => {
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Paused, endpoint, endpoint_name.clone())).unwrap();
-
+                            Message::Heartbeat => {},
+                            Message::ParticipantError(err) => {
+                                let machine = self.machines.get_by_right(&endpoint).unwrap();
+                                self.message_sender.send(UiEvents::Log(NodeType::Participant(machine.clone()), err, Severity::Error)).unwrap();
                             },
-                            Message::Executing => {
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                self.message_sender.send(UiEvents::ChangeStatusTo(ParticipantStatus::Calculating, endpoint, endpoint_name.clone())).unwrap();
-
+                            Message::ParticipantWarning(err) => {
+                                let machine = self.machines.get_by_right(&endpoint).unwrap();
+                                self.message_sender.send(UiEvents::Log(NodeType::Participant(machine.clone()), err, Severity::Warning)).unwrap();
                             },
-                            Message::Stdout(output) => {
-                                let endpoint_name = self.participants.get_by_right(&endpoint).unwrap();
-                                self.message_sender.send(UiEvents::Log(NodeType::Participant(endpoint_name.clone()), output, Severity::Stdout)).unwrap();
-
-                            }
                             _ => {
-
-                                panic!("Invalid message received by host ({:?})", message);
+                                self.invalid_message(endpoint, message);
                             }
                         }
                     }
@@ -329,70 +1430,112 @@ impl<'a> Host<'a> {
 
                     },
                     NetEvent::RemovedEndpoint(endpoint) => {
-                        //Participant disconnected without unregistering
-                        match self.participants.get_by_right(&endpoint)
-                        {
-                            Some(endpoint_name) => {
+                        self.authenticating.remove(&endpoint);
 
-                                self.message_sender.send(UiEvents::ParticipantUnregistered(endpoint_name.clone())).unwrap();
-
-                                self.participants.remove_by_right(&endpoint);
-                            }
-                            None => {
-
-                            }
+                        //Participant disconnected without unregistering
+                        self.unregister(endpoint);
+                    }
+                    NetEvent::DeserializationError(endpoint) => {
+                        if self.drop_untunnelled(endpoint) {
+                            return;
                         }
 
-
-                    }
-                    NetEvent::DeserializationError(_) => (),
+                        //Most likely a peer built from a different version, whose messages cannot be read
+                        if !self.machines.contains_right(&endpoint) {
+                            self.reject(endpoint, String::new(), format!("Could not read registration, host uses protocol version {} (midas {})", messages::PROTOCOL_VERSION, env!("CARGO_PKG_VERSION")));
+                        } else {
+                            let name = self.machines.get_by_right(&endpoint).unwrap().clone();
+                            self.message_sender.send(UiEvents::Log(NodeType::Participant(name), format!("Could not read message from participant"), Severity::Warning)).unwrap();
+                        }
+                    },
                 },
-                HostEvent::Pause(endpoint) => {
-                    self.network.send(endpoint, Message::Pause);
+                HostEvent::Pause(worker) => {
+                    self.send_to(worker, Message::Pause);
 
                 },
-                HostEvent::Play(endpoint) => {
-                    self.network.send(endpoint, Message::Play);
+                HostEvent::Play(worker) => {
+                    self.send_to(worker, Message::Play);
 
                 },
-                HostEvent::Kill(endpoint) => {
-                    self.network.send(endpoint, Message::Kill);
+                HostEvent::Kill(worker) => {
+                    self.send_to(worker, Message::Kill);
+                },
+                HostEvent::Remove(endpoint) => {
+                    if self.machines.contains_right(&endpoint) {
+                        self.drop_participant(endpoint);
+                    }
+                },
+                HostEvent::Exclude(worker, exclude) => {
+                    if exclude {
+                        self.excluded.insert(worker);
+                    } else {
+                        self.excluded.remove(&worker);
+
+                        if self.job_running {
+                            self.dispatch_idle();
+                            self.check_job_complete();
+                        }
+                    }
                 },
                 HostEvent::Begin(path) => {
-                    self.start_participants(path.as_str());
+                    //Workers between two tasks look idle to the UI, but their job is still running
+                    if self.job_running {
+                        self.message_sender.send(UiEvents::Log(NodeType::Host, format!("A job is already running, stop it before starting another."), Severity::Warning)).unwrap();
+                    } else {
+                        self.start_participants(path.as_str());
+                    }
                 },
 
                 HostEvent::PlayAll => {
-                    for (_, endpoint) in self.participants.iter() {
-                        self.network.send(*endpoint, Message::Play);
+                    for (_, worker) in self.participants.iter() {
+                        self.network.send(worker.endpoint, Message::Slot(worker.slot, Box::new(Message::Play)));
 
                     }
                 },
 
                 HostEvent::PauseAll => {
-                    for (_, endpoint) in self.participants.iter() {
-                        self.network.send(*endpoint, Message::Pause);
+                    for (_, worker) in self.participants.iter() {
+                        self.network.send(worker.endpoint, Message::Slot(worker.slot, Box::new(Message::Pause)));
+
+                    }
+                },
+
+                HostEvent::StopAll => {
+                    if self.job_running {
+                        self.message_sender.send(UiEvents::Log(NodeType::Host, format!("Job cancelled, stopping {} running tasks.", self.running_tasks.len()), Severity::Warning)).unwrap();
 
+                        self.job_running = false;
+
+                        self.stop_running_tasks();
                     }
                 },
 
                 HostEvent::KillAll => {
-                    for (_, endpoint) in self.participants.iter() {
-                        self.network.send(*endpoint, Message::Kill);
+                    //Killed participants disconnect, so make sure their tasks are not retried
+                    self.job_running = false;
+                    self.running_tasks.clear();
+                    self.retry_queue.clear();
+
+                    for (_, worker) in self.participants.iter() {
+                        self.network.send(worker.endpoint, Message::Slot(worker.slot, Box::new(Message::Kill)));
                     }
                 },
 
                 HostEvent::RemoveAll => {
-                    for (_, endpoint) in self.participants.iter() {
+                    for (_, endpoint) in self.machines.iter() {
                         self.network.remove_resource(endpoint.resource_id());
                     }
                 },
             },
+            Err(RecvTimeoutError::Timeout) => {}
             Err(e) => {
                 eprintln!("Receive error in Host::check_events() - {}", e);
             }
         }
 
+        self.check_stragglers();
+        self.check_liveness();
+
 
     }
 
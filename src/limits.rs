@@ -0,0 +1,226 @@
+// hlua has no way to interrupt running code, so limits are enforced through the raw state. The
+// instruction and wall time limits are checked by a Lua count hook, which runs every `HOOK_INTERVAL`
+// instructions and raises a Lua error once a limit is exceeded, unwinding `execute_code` the same way
+// `_check` does. A single instruction can allocate any amount of memory, so the heap is limited by an
+// allocator that refuses to grow it past the limit, which Lua raises as a memory error.
+
+use hlua::{Lua, AsMutLua};
+use lua52_sys as ffi;
+use serde::{Serialize, Deserialize};
+
+use std::cell::RefCell;
+use std::fmt;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::time::Instant;
+
+const HOOK_INTERVAL: c_int = 1000;
+
+const LIMIT_ERROR: &[u8] = b"execution limit exceeded\0";
+
+/// Resource limits for a single call of `execute_code`. `None` means unlimited
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Wall time in seconds
+    pub wall_time: Option<f64>,
+
+    /// Number of Lua VM instructions
+    pub instructions: Option<u64>,
+
+    /// Size of the Lua heap in bytes
+    pub memory: Option<u64>,
+}
+
+/// The limit that stopped a task
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    WallTime,
+    Instructions,
+    Memory,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::WallTime => write!(f, "wall time"),
+            Limit::Instructions => write!(f, "instruction"),
+            Limit::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+impl Limit {
+    /// Formats `value` in the units of this limit
+    pub fn describe(&self, value: f64) -> String {
+        match self {
+            Limit::WallTime => format!("{} seconds", value),
+            Limit::Instructions => format!("{} instructions", value),
+            Limit::Memory => format!("{} bytes", value),
+        }
+    }
+}
+
+fn min_option<T: PartialOrd>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if b < a { b } else { a }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+impl Limits {
+    pub fn is_unlimited(&self) -> bool {
+        self.wall_time.is_none() && self.instructions.is_none() && self.memory.is_none()
+    }
+
+    /// The stricter of these limits and `cap`, for each kind of limit
+    pub fn capped_by(&self, cap: &Limits) -> Limits {
+        Limits {
+            wall_time: min_option(self.wall_time, cap.wall_time),
+            instructions: min_option(self.instructions, cap.instructions),
+            memory: min_option(self.memory, cap.memory),
+        }
+    }
+
+    /// Value of `limit`, for error messages
+    pub fn value_of(&self, limit: Limit) -> f64 {
+        match limit {
+            Limit::WallTime => self.wall_time.unwrap_or(0.0),
+            Limit::Instructions => self.instructions.unwrap_or(0) as f64,
+            Limit::Memory => self.memory.unwrap_or(0) as f64,
+        }
+    }
+}
+
+struct Tracker {
+    limits: Limits,
+    started: Instant,
+    instructions: u64,
+    exceeded: Option<Limit>,
+}
+
+impl Tracker {
+    fn check(&mut self) -> Option<Limit> {
+        self.instructions += HOOK_INTERVAL as u64;
+
+        if self.limits.instructions.map_or(false, |max| self.instructions > max) {
+            self.exceeded = Some(Limit::Instructions);
+        } else if self.limits.wall_time.map_or(false, |max| self.started.elapsed().as_secs_f64() > max) {
+            self.exceeded = Some(Limit::WallTime);
+        }
+
+        self.exceeded
+    }
+}
+
+// Allocator installed while a task with a memory limit runs. Allocations are passed on to the
+// interpreter's own allocator, unless they would take the heap over `limit` bytes
+struct HeapLimit {
+    inner: ffi::lua_Alloc,
+    inner_data: *mut c_void,
+    used: usize,
+    limit: usize,
+}
+
+//Each participant runs its own Lua state on its own thread
+thread_local! {
+    static TRACKER: RefCell<Option<Tracker>> = RefCell::new(None);
+}
+
+extern "C" fn limit_hook(state: *mut ffi::lua_State, _debug: *mut ffi::lua_Debug) {
+    let exceeded = TRACKER.with(|tracker| match tracker.borrow_mut().as_mut() {
+        Some(tracker) => tracker.check(),
+        None => None
+    });
+
+    //Nothing owned is alive at this point, as `lua_error` does not return
+    if exceeded.is_some() {
+        unsafe {
+            ffi::lua_pushstring(state, LIMIT_ERROR.as_ptr() as *const c_char);
+            ffi::lua_error(state);
+        }
+    }
+}
+
+extern "C" fn limited_alloc(data: *mut c_void, block: *mut c_void, old_size: usize, new_size: usize) -> *mut c_void {
+    let heap = unsafe { &mut *(data as *mut HeapLimit) };
+
+    //When `block` is null, Lua passes the type of the new object in place of its old size
+    let old_bytes = if block.is_null() { 0 } else { old_size };
+
+    //Lua expects shrinking and freeing to always succeed, so only growth is refused
+    if new_size > old_bytes && heap.used + (new_size - old_bytes) > heap.limit {
+        TRACKER.with(|tracker| {
+            if let Ok(mut tracker) = tracker.try_borrow_mut() {
+                if let Some(tracker) = tracker.as_mut() {
+                    tracker.exceeded = Some(Limit::Memory);
+                }
+            }
+        });
+
+        return ptr::null_mut();
+    }
+
+    let result = (heap.inner)(heap.inner_data, block, old_size, new_size);
+
+    if !result.is_null() || new_size == 0 {
+        heap.used = heap.used.saturating_sub(old_bytes) + new_size;
+    }
+
+    result
+}
+
+/// Calls `f` with `limits` enforced on any Lua code it runs, returning its result and the limit that
+/// was exceeded, if any
+pub fn run_limited<'lua, T, F>(lua: &mut Lua<'lua>, limits: Limits, f: F) -> (T, Option<Limit>)
+    where F: FnOnce(&mut Lua<'lua>) -> T {
+
+    if limits.is_unlimited() {
+        return (f(lua), None);
+    }
+
+    TRACKER.with(|tracker| *tracker.borrow_mut() = Some(Tracker {
+        limits,
+        started: Instant::now(),
+        instructions: 0,
+        exceeded: None,
+    }));
+
+    let state = lua.as_mut_lua().state_ptr();
+
+    //Boxed so that its address stays the same while the allocator holds it
+    let mut heap = limits.memory.map(|max| unsafe {
+        let mut inner_data = ptr::null_mut();
+        let inner = ffi::lua_getallocf(state, &mut inner_data);
+
+        Box::new(HeapLimit {
+            inner,
+            inner_data,
+            used: ffi::lua_gc(state, ffi::LUA_GCCOUNT, 0) as usize * 1024 + ffi::lua_gc(state, ffi::LUA_GCCOUNTB, 0) as usize,
+            limit: max as usize,
+        })
+    });
+
+    unsafe {
+        ffi::lua_sethook(state, Some(limit_hook), ffi::LUA_MASKCOUNT, HOOK_INTERVAL);
+
+        if let Some(heap) = heap.as_mut() {
+            ffi::lua_setallocf(state, limited_alloc, &mut **heap as *mut HeapLimit as *mut c_void);
+        }
+    }
+
+    let result = f(lua);
+
+    unsafe {
+        ffi::lua_sethook(state, None, 0, 0);
+
+        //Blocks allocated while the limit was in place came from the inner allocator, so it can free them
+        if let Some(heap) = &heap {
+            ffi::lua_setallocf(state, heap.inner, heap.inner_data);
+        }
+    }
+
+    let exceeded = TRACKER.with(|tracker| tracker.borrow_mut().take()).and_then(|tracker| tracker.exceeded);
+
+    (result, exceeded)
+}
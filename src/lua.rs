@@ -1,4 +1,222 @@
+use hlua::{Lua, LuaError, LuaTable, AnyLuaValue};
+use serde::{Serialize, Deserialize};
 
-pub type SerdeLuaTable = Vec<(hlua::AnyLuaValue, hlua::AnyLuaValue)>;
+/// A Lua value that can be sent between host and participant. Unlike `AnyLuaValue`, tables may be
+/// nested to any depth.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SerdeLuaValue {
+    String(String),
+    Number(f64),
+    Boolean(bool),
+    Table(SerdeLuaTable),
+}
 
+pub type SerdeLuaTable = Vec<(SerdeLuaValue, SerdeLuaValue)>;
 
+// hlua can only read one level of a table at a time, so nested tables are flattened in Lua into a
+// single array of (parent row, key, value, is table) records, and rebuilt from the same layout.
+// Rows are numbered from 1 in pre-order, with 0 being the root table.
+const HELPERS: &str = r#"
+function _midas_flatten(root)
+    _midas_flat = nil
+
+    if root == nil then return end
+
+    if type(root) ~= "table" then
+        error("expected a table, got " .. type(root))
+    end
+
+    local flat = {}
+    local ancestors = {}
+    local rows = 0
+
+    local function walk(t, id)
+        if ancestors[t] then error("cannot transfer a table that contains itself") end
+        ancestors[t] = true
+
+        for k, v in pairs(t) do
+            local key_type = type(k)
+            local value_type = type(v)
+
+            if key_type ~= "string" and key_type ~= "number" and key_type ~= "boolean" then
+                error("cannot transfer a table key of type " .. key_type)
+            end
+
+            rows = rows + 1
+            local base = #flat
+
+            flat[base + 1] = id
+            flat[base + 2] = k
+
+            if value_type == "table" then
+                flat[base + 3] = rows
+                flat[base + 4] = true
+                walk(v, rows)
+            elseif value_type == "string" or value_type == "number" or value_type == "boolean" then
+                flat[base + 3] = v
+                flat[base + 4] = false
+            else
+                error("cannot transfer a value of type " .. value_type)
+            end
+        end
+
+        ancestors[t] = nil
+    end
+
+    walk(root, 0)
+
+    _midas_flat = flat
+end
+
+function _midas_unflatten()
+    local tables = { [0] = {} }
+
+    for base = 1, #_midas_flat, 4 do
+        local value = _midas_flat[base + 2]
+
+        if _midas_flat[base + 3] then
+            value = {}
+            tables[(base + 3) / 4] = value
+        end
+
+        tables[_midas_flat[base]][_midas_flat[base + 1]] = value
+    end
+
+    _midas_flat = nil
+
+    return tables[0]
+end
+"#;
+
+impl SerdeLuaValue {
+    fn from_any(value: AnyLuaValue) -> Option<Self> {
+        match value {
+            AnyLuaValue::LuaString(string) => Some(SerdeLuaValue::String(string)),
+            AnyLuaValue::LuaNumber(number) => Some(SerdeLuaValue::Number(number)),
+            AnyLuaValue::LuaBoolean(boolean) => Some(SerdeLuaValue::Boolean(boolean)),
+            _ => None
+        }
+    }
+
+    // Nested tables are flattened before being pushed to Lua, so only a key can get here as a table
+    fn to_any(&self) -> Result<AnyLuaValue, LuaError> {
+        match self {
+            SerdeLuaValue::String(string) => Ok(AnyLuaValue::LuaString(string.clone())),
+            SerdeLuaValue::Number(number) => Ok(AnyLuaValue::LuaNumber(*number)),
+            SerdeLuaValue::Boolean(boolean) => Ok(AnyLuaValue::LuaBoolean(*boolean)),
+            SerdeLuaValue::Table(_) => Err(LuaError::ExecutionError(String::from("cannot use a table as a table key")))
+        }
+    }
+
+    fn to_key(&self) -> Result<AnyLuaValue, LuaError> {
+        match self {
+            SerdeLuaValue::Number(number) if number.is_nan() => Err(LuaError::ExecutionError(String::from("cannot use NaN as a table key"))),
+            key => key.to_any()
+        }
+    }
+}
+
+/// Registers the Lua functions used to move nested tables in and out of `lua`. Must be called before
+/// `evaluate_table` or `set_table`.
+pub fn load_helpers(lua: &mut Lua) -> Result<(), LuaError> {
+    lua.execute::<()>(HELPERS)
+}
+
+/// Evaluates `expression` in Lua and reads back the table it returns, or `None` if it returns nil
+pub fn evaluate_table(lua: &mut Lua, expression: &str) -> Result<Option<SerdeLuaTable>, LuaError> {
+    lua.execute::<()>(format!("_midas_flatten({})", expression).as_str())?;
+
+    let mut flat: Vec<(usize, AnyLuaValue)> = match lua.get::<LuaTable<_>, _>("_midas_flat") {
+        Some(mut table) => table.iter::<AnyLuaValue, AnyLuaValue>()
+            .map(|pair| pair.unwrap())
+            .map(|(index, value)| match index {
+                AnyLuaValue::LuaNumber(index) => (index as usize, value),
+                _ => unreachable!("`_midas_flat` is always an array")
+            })
+            .collect(),
+        None => return Ok(None)
+    };
+
+    flat.sort_by_key(|(index, _)| *index);
+
+    let records: Vec<_> = flat.into_iter().map(|(_, value)| value).collect();
+
+    unflatten(&records).map(Some).map_err(LuaError::ExecutionError)
+}
+
+/// Rebuilds a table from the records created by `_midas_flatten`, failing if it holds strings that
+/// are not valid UTF-8
+pub fn unflatten(records: &[AnyLuaValue]) -> Result<SerdeLuaTable, String> {
+    // Children always come after their parent, so by walking backwards every nested table is
+    // complete by the time we reach the row that holds it
+    let mut tables: Vec<SerdeLuaTable> = vec![Vec::new(); records.len() / 4 + 1];
+
+    for (row, record) in records.chunks(4).enumerate().rev() {
+        let parent = match record[0] {
+            AnyLuaValue::LuaNumber(parent) => parent as usize,
+            _ => unreachable!("Parent row is always a number")
+        };
+
+        let key = SerdeLuaValue::from_any(record[1].clone());
+
+        let value = match record[3] {
+            AnyLuaValue::LuaBoolean(true) => {
+                let mut nested = std::mem::take(&mut tables[row + 1]);
+                nested.reverse();
+                Some(SerdeLuaValue::Table(nested))
+            }
+            _ => SerdeLuaValue::from_any(record[2].clone())
+        };
+
+        match (key, value) {
+            (Some(key), Some(value)) => tables[parent].push((key, value)),
+            _ => return Err(String::from("cannot transfer a string that is not valid UTF-8"))
+        }
+    }
+
+    let mut root = std::mem::take(&mut tables[0]);
+    root.reverse();
+
+    Ok(root)
+}
+
+/// Creates (or replaces) the global `name` with the contents of `table`. Tables received from the
+/// network may hold keys Lua cannot use, in which case nothing is set and an error is returned
+pub fn set_table(lua: &mut Lua, name: &str, table: &SerdeLuaTable) -> Result<(), LuaError> {
+    fn flatten(table: &SerdeLuaTable, parent: usize, rows: &mut usize, flat: &mut Vec<AnyLuaValue>) -> Result<(), LuaError> {
+        for (key, value) in table.iter() {
+            *rows += 1;
+            let row = *rows;
+
+            flat.push(AnyLuaValue::LuaNumber(parent as f64));
+            flat.push(key.to_key()?);
+
+            match value {
+                SerdeLuaValue::Table(nested) => {
+                    flat.push(AnyLuaValue::LuaNumber(row as f64));
+                    flat.push(AnyLuaValue::LuaBoolean(true));
+                    flatten(nested, row, rows, flat)?;
+                }
+                _ => {
+                    flat.push(value.to_any()?);
+                    flat.push(AnyLuaValue::LuaBoolean(false));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut flat = Vec::new();
+    flatten(table, 0, &mut 0, &mut flat)?;
+
+    {
+        let mut arr = lua.empty_array("_midas_flat");
+
+        for (i, value) in flat.into_iter().enumerate() {
+            arr.set(AnyLuaValue::LuaNumber((i + 1) as f64), value);
+        }
+    }
+
+    lua.execute::<()>(format!("{} = _midas_unflatten()", name).as_str())
+}
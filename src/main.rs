@@ -3,25 +3,44 @@ mod participant;
 mod messages;
 mod lua;
 mod ui;
+mod headless;
+mod output;
+mod auth;
+mod tls;
+mod signing;
+mod sandbox;
+mod limits;
+mod discovery;
+mod slot;
 
 extern crate clap;
 extern crate serde;
 
 use clap::{crate_version, Arg, App, SubCommand};
 use std::thread;
+use std::process;
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+use std::path::PathBuf;
 
 use crate::ui::Panel;
 use crate::host::Host;
-
-use crate::messages::{HostEvent, UiEvents};
+use crate::headless::Headless;
+use crate::output::{Output, OutputFormat};
+use crate::tls::{ServerTls, ClientTls};
+use crate::signing::{SigningKey, TrustedKeys};
+use crate::sandbox::SandboxProfile;
+use crate::limits::Limits;
+
+use crate::messages::{HostEvent, UiEvents, MAX_TIMEOUT};
 use crossbeam_channel::unbounded;
-use message_io::network::{Network, Transport, NetEvent};
 
 fn main() {
 
 
 
-    use std::net::SocketAddrV4;
+    use std::net::SocketAddr;
     use std::str::FromStr;
 
 
@@ -34,14 +53,28 @@ fn main() {
             .short("a")
             .long("address")
             .takes_value(true)
-            .help("Socket address to host/connect to. Pleas specify Ip address and port number, such as '192.168.0.1:4000'.")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Socket address to host/connect to. Pleas specify Ip address or host name and port number, such as '192.168.0.1:4000', '[::1]:4000' or 'buildbox.local:4000'. The host can be given several addresses to listen at, for example '-a [::]:4000 -a 0.0.0.0:4000'. Participants using --discover do not need one.")
             .validator( |value|
-                    match SocketAddrV4::from_str(value.as_str()) {
-                        Ok(_) => Ok(()),
-                        Err(e) => Err(e.to_string())
+                    //Host names are only checked for their form here, they are looked up when connecting or listening
+                    match (SocketAddr::from_str(value.as_str()), value.rfind(':')) {
+                        (Ok(_), _) => Ok(()),
+                        (Err(_), Some(colon)) if colon > 0 && !value[..colon].contains(':') && value[colon + 1..].parse::<u16>().is_ok() => Ok(()),
+                        _ => Err(format!("{} is not a socket address or host:port", value))
                     }
-                )
-            .required(true))
+                ))
+        .arg(Arg::with_name("secret")
+            .long("secret")
+            .takes_value(true)
+            .env("MIDAS_SECRET")
+            .hide_env_values(true)
+            .conflicts_with("secret file")
+            .help("Shared secret that the host and participants use to authenticate each other. Both must be given the same secret."))
+        .arg(Arg::with_name("secret file")
+            .long("secret-file")
+            .takes_value(true)
+            .help("File containing the shared secret, used instead of --secret."))
         .subcommand(SubCommand::with_name("host")
             .arg(Arg::with_name("Lua script")
                 .short("s")
@@ -57,6 +90,92 @@ fn main() {
                     }
                 )
                 .required(true))
+            .arg(Arg::with_name("headless")
+                .long("headless")
+                .help("Runs the script once without the terminal UI, then exits. The exit code is 0 if the job finished, 1 if it failed and 2 if no participants registered."))
+            .arg(Arg::with_name("participant count")
+                .short("p")
+                .long("participants")
+                .help("Number of participant processes to wait for before starting the job in headless mode, however many threads each one runs. Defaults to 1.")
+                .validator(|value|
+                    match value.parse::<usize>() {
+                        Ok(0) => Err(format!("At least one participant is needed.")),
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(format!("Invalid number of participants: Could not convert {} to number because '{}'.", value, e))
+                    }
+                )
+                .takes_value(true)
+                .requires("headless"))
+            .arg(Arg::with_name("wait timeout")
+                .short("w")
+                .long("wait")
+                .help("Seconds to wait for participants in headless mode. Once elapsed the job starts with the participants that have registered.")
+                .validator(|value|
+                    match value.parse::<f64>() {
+                        Ok(seconds) if seconds >= 0.0 && seconds <= MAX_TIMEOUT => Ok(()),
+                        Ok(seconds) if seconds < 0.0 => Err(format!("The timeout cannot be negative.")),
+                        Ok(_) => Err(format!("The timeout cannot be more than {} seconds.", MAX_TIMEOUT)),
+                        Err(e) => Err(format!("Invalid timeout: Could not convert {} to number because '{}'.", value, e))
+                    }
+                )
+                .takes_value(true)
+                .requires("headless"))
+            .arg(Arg::with_name("log file")
+                .short("l")
+                .long("log")
+                .help("File to write logs to in headless mode. If no file is supplied, logs are written to stdout.")
+                .takes_value(true)
+                .requires("headless"))
+            .arg(Arg::with_name("output file")
+                .short("o")
+                .long("output")
+                .help("File to save the results, the return value of `interpret_results` and details of each finished job to.")
+                .takes_value(true))
+            .arg(Arg::with_name("output format")
+                .short("f")
+                .long("format")
+                .help("Format of the output file. If no format is supplied, it is chosen from the file extension.")
+                .possible_values(&["json", "csv"])
+                .case_insensitive(true)
+                .takes_value(true)
+                .requires("output file"))
+            .arg(Arg::with_name("output directory")
+                .short("d")
+                .long("output-dir")
+                .help("Directory that the script's `_read_file`, `_write_file` and `_append_file` functions are confined to. Defaults to the current directory.")
+                .takes_value(true))
+            .arg(Arg::with_name("tls certificate")
+                .long("tls-cert")
+                .help("PEM certificate chain. Together with --tls-key, encrypts connections with participants using TLS.")
+                .takes_value(true)
+                .requires("tls key"))
+            .arg(Arg::with_name("tls key")
+                .long("tls-key")
+                .help("PEM private key (PKCS8 or RSA) of the TLS certificate.")
+                .takes_value(true)
+                .requires("tls certificate"))
+            .arg(Arg::with_name("signing key")
+                .long("signing-key")
+                .help("File containing a hex encoded Ed25519 secret key, used to sign scripts sent to participants.")
+                .takes_value(true))
+            .arg(Arg::with_name("announce")
+                .long("announce")
+                .help("Announces the host on the local network, so participants started with --discover can find it."))
+            .arg(Arg::with_name("job name")
+                .long("job")
+                .help("Job name to announce. Defaults to the name of the script file.")
+                .takes_value(true)
+                .requires("announce"))
+            .arg(Arg::with_name("heartbeat timeout")
+                .long("heartbeat-timeout")
+                .help("Seconds without hearing from a participant before it is marked unresponsive and its task is given to another participant. Defaults to 10.")
+                .validator(|value|
+                    match value.parse::<f64>() {
+                        Ok(seconds) if seconds > 0.0 && seconds <= MAX_TIMEOUT => Ok(()),
+                        _ => Err(format!("Invalid heartbeat timeout: {} is not a positive number of seconds up to {}.", value, MAX_TIMEOUT))
+                    }
+                )
+                .takes_value(true))
             .about("Executes Midas as the host"))
         .subcommand(SubCommand::with_name("participant")
             .arg(Arg::with_name("participant name")
@@ -68,19 +187,97 @@ fn main() {
             .arg(Arg::with_name("thread count")
                 .short("t")
                 .long("threads")
-                .help("Number of threads. If no number is supplied, the value is calculated automatically.")
+                .help("Number of worker threads, each of which runs one task at a time. If no number is supplied, the value is calculated automatically.")
                 .validator(|value|
                     match value.parse::<i32>() {
+                        Ok(count) if count < 1 => Err(format!("Invalid number of threads: There must be at least one thread.")),
                         Ok(_) => Ok(()),
                         Err(e) => Err(format!("Invalid number of threads: Could not convert {} to number because '{}'.", value, e))
                     }
                 )
                 .takes_value(true)
                 .required(false))
+            .arg(Arg::with_name("discover")
+                .long("discover")
+                .help("Finds the host on the local network instead of using --address. The host must be started with --announce."))
+            .arg(Arg::with_name("job name")
+                .long("job")
+                .help("Only connects to a host announcing this job, used with --discover.")
+                .takes_value(true)
+                .requires("discover"))
+            .arg(Arg::with_name("tls ca")
+                .long("tls-ca")
+                .help("PEM CA certificates trusted to sign the host's certificate. Connects to the host using TLS.")
+                .takes_value(true)
+                .requires("tls server name")
+                .conflicts_with("tls fingerprint"))
+            .arg(Arg::with_name("tls server name")
+                .long("tls-name")
+                .help("Name the host's certificate must be issued for, used with --tls-ca.")
+                .takes_value(true)
+                .requires("tls ca"))
+            .arg(Arg::with_name("tls fingerprint")
+                .long("tls-fingerprint")
+                .help("SHA-256 fingerprint of the host's certificate, as shown in the host's log. Connects to the host using TLS and trusts only that certificate.")
+                .takes_value(true))
+            .arg(Arg::with_name("trusted keys")
+                .long("trusted-keys")
+                .help("File of hex encoded Ed25519 public keys, one per line. Only scripts signed by one of these keys are run.")
+                .takes_value(true))
+            .arg(Arg::with_name("sandbox")
+                .long("sandbox")
+                .help("Runs scripts with only the math, string and table libraries and the Midas functions, without os, io, require or loading code from files."))
+            .arg(Arg::with_name("allow read")
+                .long("allow-read")
+                .help("Lets sandboxed scripts read files inside this directory with `_read_file`.")
+                .takes_value(true)
+                .requires("sandbox"))
+            .arg(Arg::with_name("allow time")
+                .long("allow-time")
+                .help("Lets sandboxed scripts use `os.time` and `os.clock`.")
+                .requires("sandbox"))
+            .arg(Arg::with_name("max time")
+                .long("max-time")
+                .help("Longest time in seconds a single task may run for, whatever the host asks for.")
+                .validator(|value|
+                    match value.parse::<f64>() {
+                        Ok(seconds) if seconds > 0.0 => Ok(()),
+                        _ => Err(format!("Invalid time limit: {} is not a positive number of seconds.", value))
+                    }
+                )
+                .takes_value(true))
+            .arg(Arg::with_name("max instructions")
+                .long("max-instructions")
+                .help("Most Lua instructions a single task may run, whatever the host asks for.")
+                .validator(|value|
+                    match value.parse::<u64>() {
+                        Ok(_) => Ok(()),
+                        Err(e) => Err(format!("Invalid instruction limit: Could not convert {} to number because '{}'.", value, e))
+                    }
+                )
+                .takes_value(true))
+            .arg(Arg::with_name("max memory")
+                .long("max-memory")
+                .help("Largest size in megabytes the Lua heap may grow to while running a task, whatever the host asks for.")
+                .validator(|value|
+                    match value.parse::<f64>() {
+                        Ok(megabytes) if megabytes > 0.0 => Ok(()),
+                        _ => Err(format!("Invalid memory limit: {} is not a positive number of megabytes.", value))
+                    }
+                )
+                .takes_value(true))
             .about("Executes Midas as a participant"))
         .get_matches();
 
-    let ip_address = app_matches.value_of("socket address").unwrap();
+    let ip_addresses: Vec<String> = app_matches.values_of("socket address").map_or(Vec::new(), |addresses| addresses.map(String::from).collect());
+
+    let secret = match auth::read_secret(app_matches.value_of("secret"), app_matches.value_of("secret file")) {
+        Ok(secret) => secret,
+        Err(e) => {
+            println!("Error - {}", e);
+            process::exit(1);
+        }
+    };
 
     //Setup the channels of communication between Host code and ui
     let (command_sender, command_receiver) = unbounded::<HostEvent>();
@@ -89,9 +286,86 @@ fn main() {
     match app_matches.subcommand() {
         ("host", host_matches) => {
 
-            match Host::new(command_receiver, command_sender.clone(), message_sender,ip_address) {
+            let host_matches = host_matches.unwrap();
+
+            let output = host_matches.value_of("output file").map(|path| Output {
+                path: String::from(path),
+                format: match host_matches.value_of("output format") {
+                    Some(name) => OutputFormat::from_name(name).unwrap(),
+                    None => OutputFormat::from_path(path)
+                },
+            });
+
+            let output_directory = PathBuf::from(host_matches.value_of("output directory").unwrap_or("."));
+
+            let tls = match (host_matches.value_of("tls certificate"), host_matches.value_of("tls key")) {
+                (Some(certificate), Some(key)) => match ServerTls::load(certificate, key) {
+                    Ok(tls) => Some(tls),
+                    Err(e) => {
+                        println!("Host Error - {}", e);
+                        process::exit(1);
+                    }
+                },
+                _ => None
+            };
+
+            let signing_key = match host_matches.value_of("signing key").map(SigningKey::load).transpose() {
+                Ok(key) => key,
+                Err(e) => {
+                    println!("Host Error - {}", e);
+                    process::exit(1);
+                }
+            };
+
+            if ip_addresses.is_empty() {
+                println!("Host Error - An address to listen at must be given with --address");
+                process::exit(1);
+            }
+
+            let announce = if host_matches.is_present("announce") {
+                let script_name = std::path::Path::new(host_matches.value_of("Lua script").unwrap()).file_stem().unwrap().to_string_lossy().into_owned();
+
+                Some(host_matches.value_of("job name").map(String::from).unwrap_or(script_name))
+            } else {
+                None
+            };
+
+            let heartbeat_timeout = Duration::from_secs_f64(host_matches.value_of("heartbeat timeout").unwrap_or("10").parse::<f64>().unwrap());
+
+            match Host::new(command_receiver, command_sender.clone(), message_sender, &ip_addresses, output, output_directory, secret, tls, signing_key, heartbeat_timeout, announce) {
                 Ok(mut host) => {
-                    let script_path = host_matches.unwrap().value_of("Lua script").unwrap();
+                    let script_path = host_matches.value_of("Lua script").unwrap();
+
+                    if host_matches.is_present("headless") {
+                        let participants_needed = match host_matches.value_of("participant count") {
+                            Some(count) => count.parse::<usize>().unwrap(),
+                            None => 1
+                        };
+
+                        let wait = host_matches.value_of("wait timeout")
+                            .map(|seconds| Duration::from_secs_f64(seconds.parse::<f64>().unwrap()));
+
+                        let log: Option<Box<dyn Write>> = match host_matches.value_of("log file") {
+                            Some(path) => match File::create(path) {
+                                Ok(file) => Some(Box::new(file)),
+                                Err(e) => {
+                                    println!("Host Error - Could not create log file ({}) - {}", path, e);
+                                    process::exit(headless::EXIT_JOB_FAILED);
+                                }
+                            },
+                            None => None
+                        };
+
+                        let mut headless = Headless::new(command_sender.clone(), message_receiver, script_path, log, participants_needed, wait);
+
+                        thread::spawn(move ||
+                            loop {
+                                host.check_events()
+                            }
+                        );
+
+                        process::exit(headless.run());
+                    }
 
                     let mut panel = Panel::new(command_sender.clone(), message_receiver, script_path);
 
@@ -105,6 +379,10 @@ fn main() {
                 },
                 Err(error) => {
                     println!("Host Error - {}", error);
+
+                    if host_matches.is_present("headless") {
+                        process::exit(headless::EXIT_JOB_FAILED);
+                    }
                 }
             }
 
@@ -124,46 +402,67 @@ fn main() {
 
             let participant_name = participant_matches.unwrap().value_of("participant name").unwrap();
 
-            loop
-            {
-                println!("Searching for host...");
-
-                {
-                    let mut network = Network::new(move |_: NetEvent<()>| {});
+            let tls = match (participant_matches.unwrap().value_of("tls ca"), participant_matches.unwrap().value_of("tls fingerprint")) {
+                (Some(ca), _) => Some(ClientTls::with_ca(ca, participant_matches.unwrap().value_of("tls server name").unwrap())),
+                (None, Some(fingerprint)) => Some(ClientTls::with_fingerprint(fingerprint)),
+                (None, None) => None
+            };
 
+            let tls = match tls.transpose() {
+                Ok(tls) => tls,
+                Err(e) => {
+                    println!("Participant Error - {}", e);
+                    process::exit(1);
+                }
+            };
 
-                    while let Err(_) = network.connect(Transport::Tcp, ip_address) {
+            let sandbox = if participant_matches.unwrap().is_present("sandbox") {
+                Some(SandboxProfile {
+                    read_directory: participant_matches.unwrap().value_of("allow read").map(PathBuf::from),
+                    allow_time: participant_matches.unwrap().is_present("allow time"),
+                })
+            } else {
+                None
+            };
 
-                    }
+            //Caps on the limits the host sets for each job
+            let local_limits = Limits {
+                wall_time: participant_matches.unwrap().value_of("max time").map(|seconds| seconds.parse::<f64>().unwrap()),
+                instructions: participant_matches.unwrap().value_of("max instructions").map(|count| count.parse::<u64>().unwrap()),
+                memory: participant_matches.unwrap().value_of("max memory").map(|megabytes| (megabytes.parse::<f64>().unwrap() * 1024.0 * 1024.0) as u64),
+            };
 
+            let trusted_keys = match participant_matches.unwrap().value_of("trusted keys").map(TrustedKeys::load).transpose() {
+                Ok(keys) => keys,
+                Err(e) => {
+                    println!("Participant Error - {}", e);
+                    process::exit(1);
                 }
+            };
 
-                println!("Found host!");
-
-                crossbeam::thread::scope(|s| {
-                    let participant_name = participant_name;
-                    let ip_address = ip_address;
-
-                    for i in 0..thread_count {
-                        s.builder()
-                            .name(format!("thread_{}-{}", &participant_name, i))
-                            .spawn(move |_| {
-                                let mut participant = participant::Participant::new(
-                                    if thread_count == 1 {
-                                        format!("{}", participant_name)
-                                    } else {
-                                        format!("{}-{:03}", participant_name, i)
-                                    }, ip_address).unwrap();
-
-                                while let Ok(_) = participant.tick() {}
-                            }).unwrap();
+            let host_address = if participant_matches.unwrap().is_present("discover") {
+                participant::HostAddress::Discover(participant_matches.unwrap().value_of("job name").map(String::from))
+            } else {
+                match ip_addresses.as_slice() {
+                    [address] => participant::HostAddress::Fixed(address.clone()),
+                    [] => {
+                        println!("Participant Error - The host's address must be given with --address, or found with --discover");
+                        process::exit(1);
+                    },
+                    _ => {
+                        println!("Participant Error - Participants connect to a single --address");
+                        process::exit(1);
                     }
-                }).unwrap();
+                }
+            };
 
-                println!("Disconnected.");
+            //The participant reconnects if the host goes away, and only stops once all of its threads have
+            //been killed or it is rejected by the host
+            let mut participant = participant::Participant::new(String::from(participant_name), host_address, thread_count, secret, tls, trusted_keys, sandbox, local_limits);
 
+            while participant.tick().is_ok() {}
 
-            }
+            println!("All threads have stopped.");
 
         },
         _ => unreachable!()
@@ -4,29 +4,125 @@ use message_io::network::Endpoint;
 use message_io::network::NetEvent;
 
 use crate::lua::SerdeLuaTable;
+use crate::limits::{Limits, Limit};
 use tui::style::{Style, Color, Modifier};
 use tui::widgets::Cell;
 
+use std::time::Duration;
+
+/// Version of the messages exchanged between host and participant. Must be increased whenever
+/// `Message` or `Registration` change, since peers with different versions cannot read each other
+pub const PROTOCOL_VERSION: u32 = 11;
+
+/// How often participants tell the host they are still alive
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Longest timeout in seconds that the command line or a script may set (about 136 years), as a
+/// `Duration` cannot be made from every `f64`
+pub const MAX_TIMEOUT: f64 = u32::MAX as f64;
+
+/// Capabilities of this build, sent by participants when they register. The host refuses
+/// participants that lack any of its own
+pub const FEATURES: &[&str] = &["nested_tables", "partial_results", "stop"];
+
+/// Sent by a participant when it connects, so the host can check it is compatible
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Registration {
+    //Kept first so that it is read correctly even if the rest of the struct changes
+    pub protocol_version: u32,
+    pub name: String,
+    pub midas_version: String,
+
+    //Number of worker slots, each of which runs one task at a time
+    pub threads: usize,
+    pub os: String,
+    pub arch: String,
+    pub lua_version: String,
+    pub features: Vec<String>,
+
+    //Random bytes the host must answer to prove it knows the shared secret, if the participant has one
+    pub challenge: Option<Vec<u8>>,
+
+    //Token the host gave the participant when it last registered, sent when registering again after
+    //losing the connection so the host can replace a connection it has not yet noticed is dead
+    pub session: Option<Vec<u8>>,
+}
+
+impl Registration {
+    pub fn new(name: String, threads: usize, lua_version: String, challenge: Option<Vec<u8>>, session: Option<Vec<u8>>) -> Self {
+        Registration {
+            protocol_version: PROTOCOL_VERSION,
+            name,
+            midas_version: String::from(env!("CARGO_PKG_VERSION")),
+            threads,
+            os: String::from(std::env::consts::OS),
+            arch: String::from(std::env::consts::ARCH),
+            lua_version,
+            features: FEATURES.iter().map(|feature| String::from(*feature)).collect(),
+            challenge,
+            session,
+        }
+    }
+
+    /// Reason the host cannot accept this participant, if any
+    pub fn incompatibility(&self) -> Option<String> {
+        if self.protocol_version != PROTOCOL_VERSION {
+            return Some(format!("Participant uses protocol version {} (midas {}), host uses protocol version {} (midas {})",
+                                self.protocol_version, self.midas_version, PROTOCOL_VERSION, env!("CARGO_PKG_VERSION")));
+        }
+
+        let missing: Vec<&str> = FEATURES.iter()
+            .filter(|feature| !self.features.iter().any(|supported| supported == **feature))
+            .cloned()
+            .collect();
+
+        if !missing.is_empty() {
+            return Some(format!("Participant does not support {}", missing.join(", ")));
+        }
+
+        None
+    }
+}
+
+/// Messages are encoded with the index of their variant, so `Register` and `Rejected` must stay the
+/// first two variants. Peers of any version can then read each other's registration and rejection,
+/// and new variants go after them
 #[derive(Serialize, Deserialize, Debug)]
 pub enum Message {
+    /* Handshake, never moved */
+
+    Register(Registration),
+    Rejected(String),
+
     /* Host to participant */
 
-    Code(String),
+    //Script source, the functions participants may be asked to call, and the signature of both if
+    //the host has a signing key
+    Code(String, Vec<String>, Option<Vec<u8>>),
+    Limits(Limits),
     VectorHTP(SerdeLuaTable),
-    Execute,
+    Execute(String),
 
     Play,
     Pause,
     Stop,
     Kill,
 
+    //The host's challenge for the participant, and its answer to the participant's challenge
+    Challenge(Vec<u8>, Vec<u8>),
+
+    //Sent once the participant is registered, with the token that lets it take over its name if it reconnects
+    Registered(Vec<u8>),
+
     /* Participant to Host */
 
     VectorPTH(SerdeLuaTable),
+    Partial(SerdeLuaTable),
 
     Progress(f32),
 
     ParticipantError(String),
+    LimitExceeded(Limit, f64),
     ParticipantWarning(String),
     Whisper(String),
     Stdout(String),
@@ -34,9 +130,32 @@ pub enum Message {
     Paused,
     Continued,
     Executing,
+    Cancelled,
+
+    //Sent every `HEARTBEAT_INTERVAL` whatever the script is doing
+    Heartbeat,
 
-    Register(String),
-    Unregister
+    ChallengeResponse(Vec<u8>),
+    Unregister,
+
+    /* Either way */
+
+    //A message for or from one worker slot of a participant, rather than the whole connection
+    Slot(usize, Box<Message>),
+}
+
+/// One worker slot of a participant. Each participant process has a single connection to the host,
+/// shared by all of its slots
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Worker {
+    pub endpoint: Endpoint,
+    pub slot: usize,
+}
+
+impl Worker {
+    pub fn new(endpoint: Endpoint, slot: usize) -> Self {
+        Worker { endpoint, slot }
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
@@ -45,6 +164,8 @@ pub enum ParticipantStatus {
     Calculating,
     Paused,
 
+    //No message has arrived from the participant within the heartbeat timeout
+    Unresponsive,
 }
 
 impl ParticipantStatus {
@@ -53,6 +174,7 @@ impl ParticipantStatus {
             ParticipantStatus::Idle => Color::Green,
             ParticipantStatus::Calculating => Color::Rgb(255, 255, 0),
             ParticipantStatus::Paused => Color::Rgb(255, 128, 0),
+            ParticipantStatus::Unresponsive => Color::Rgb(212, 65, 67),
         }
     }
 }
@@ -68,15 +190,28 @@ pub enum Severity {
 }
 
 impl Severity {
+    pub fn to_str(& self) -> &'static str {
+        match self {
+            Severity::Starting => "STARTING",
+            Severity::Error => "ERROR",
+            Severity::Warning => "WARNING",
+            Severity::Result => "RESULT",
+            Severity::Stdout => "STDOUT",
+            Severity::Info => "INFO",
+        }
+    }
+
     pub fn to_cell(& self) -> Cell {
+        let cell = Cell::from(self.to_str());
+
         match self {
 
-            Severity::Starting => Cell::from("STARTING").style(Style::default().fg(Color::Rgb(108, 186, 133))),
-            Severity::Error => Cell::from("ERROR").style(Style::default().fg(Color::Rgb(212, 65, 67))),
-            Severity::Warning => Cell::from("WARNING").style(Style::default().fg(Color::Rgb(207, 114, 65))),
-            Severity::Result => Cell::from("RESULT").style(Style::default().fg(Color::Rgb(221, 183, 45))),
-            Severity::Stdout => Cell::from("STDOUT").style(Style::default().fg(Color::Rgb(178, 214, 90))),
-            Severity::Info => Cell::from("INFO").style(Style::default()),
+            Severity::Starting => cell.style(Style::default().fg(Color::Rgb(108, 186, 133))),
+            Severity::Error => cell.style(Style::default().fg(Color::Rgb(212, 65, 67))),
+            Severity::Warning => cell.style(Style::default().fg(Color::Rgb(207, 114, 65))),
+            Severity::Result => cell.style(Style::default().fg(Color::Rgb(221, 183, 45))),
+            Severity::Stdout => cell.style(Style::default().fg(Color::Rgb(178, 214, 90))),
+            Severity::Info => cell.style(Style::default()),
         }
     }
 }
@@ -87,6 +222,13 @@ pub enum NodeType {
 }
 
 impl NodeType {
+    pub fn to_str(& self) -> &str {
+        match self {
+            NodeType::Host => "Host",
+            NodeType::Participant(name) => name.as_str(),
+        }
+    }
+
     pub fn to_cell(& self) -> Cell {
         match self {
             NodeType::Host => Cell::from("Host").style(Style::default().fg(Color::Rgb(37, 158, 175))),
@@ -96,25 +238,36 @@ impl NodeType {
 }
 
 pub enum UiEvents {
-    ChangeStatusTo(ParticipantStatus, Endpoint, String),
+    ChangeStatusTo(ParticipantStatus, Worker, String),
 
     ParticipantProgress(String, f32),
 
     Log(NodeType, String, Severity),
 
-    ParticipantRegistered(Endpoint, String),
+    ParticipantRegistered(Worker, String),
     ParticipantUnregistered(String),
 
+    //A participant process, whose threads are registered separately
+    MachineRegistered(Endpoint, String),
+    MachineUnregistered(Endpoint),
+
     InterpretResultsReturn(String),
 
+    //The job ended without a result, or could not be started
+    JobFailed(String),
+
 }
 
 
 pub enum HostEvent {
     Network(NetEvent<Message>),
-    Pause(Endpoint),
-    Play(Endpoint),
-    Kill(Endpoint),
+    Pause(Worker),
+    Play(Worker),
+    Kill(Worker),
+
+    //Drops the participant's connection, and so all of its workers
+    Remove(Endpoint),
+    Exclude(Worker, bool),
 
     Begin(String),
 
@@ -122,5 +275,81 @@ pub enum HostEvent {
 
     PlayAll,
     PauseAll,
+    StopAll,
     KillAll,
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A registration laid out as it was at protocol version 8, from an older peer whose `Message` also
+    // starts with `Register`
+    #[derive(Serialize)]
+    struct RegistrationV8 {
+        protocol_version: u32,
+        name: String,
+        midas_version: String,
+        threads: usize,
+        os: String,
+        arch: String,
+        lua_version: String,
+        features: Vec<String>,
+        challenge: Option<Vec<u8>>,
+        reconnecting: bool,
+    }
+
+    #[derive(Serialize)]
+    enum MessageV8 {
+        Register(RegistrationV8),
+    }
+
+    fn old_registration() -> RegistrationV8 {
+        RegistrationV8 {
+            protocol_version: 8,
+            name: String::from("laptop"),
+            midas_version: String::from("0.2.17"),
+            threads: 4,
+            os: String::from("linux"),
+            arch: String::from("x86_64"),
+            lua_version: String::from("Lua 5.2"),
+            features: vec![String::from("nested_tables")],
+            challenge: None,
+            reconnecting: false,
+        }
+    }
+
+    #[test]
+    fn register_from_older_version_is_read_and_refused() {
+        let bytes = bincode::serialize(&MessageV8::Register(old_registration())).unwrap();
+
+        match bincode::deserialize::<Message>(&bytes).unwrap() {
+            Message::Register(registration) => {
+                assert_eq!(registration.protocol_version, 8);
+                assert_eq!(registration.name, "laptop");
+                assert!(registration.incompatibility().unwrap().contains("protocol version 8"));
+            },
+            message => panic!("Read {:?} instead of a registration", message)
+        }
+    }
+
+    #[test]
+    fn rejection_keeps_its_index() {
+        let bytes = bincode::serialize(&Message::Rejected(String::from("Participant uses protocol version 8"))).unwrap();
+
+        assert_eq!(&bytes[..4], &1u32.to_le_bytes());
+
+        match bincode::deserialize::<Message>(&bytes).unwrap() {
+            Message::Rejected(reason) => assert_eq!(reason, "Participant uses protocol version 8"),
+            message => panic!("Read {:?} instead of a rejection", message)
+        }
+    }
+
+    #[test]
+    fn register_keeps_its_index() {
+        let registration = Registration::new(String::from("laptop"), 1, String::from("Lua 5.2"), None, None);
+        let bytes = bincode::serialize(&Message::Register(registration)).unwrap();
+
+        assert_eq!(&bytes[..4], &0u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::lua::{SerdeLuaTable, SerdeLuaValue};
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map, Value};
+
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Json,
+    Csv,
+}
+
+impl OutputFormat {
+    /// Parses the value of the `--format` option
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(OutputFormat::Json),
+            "csv" => Some(OutputFormat::Csv),
+            _ => None
+        }
+    }
+
+    /// Guesses the format from the extension of `path`, defaulting to JSON
+    pub fn from_path(path: &str) -> Self {
+        match std::path::Path::new(path).extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("csv") => OutputFormat::Csv,
+            _ => OutputFormat::Json
+        }
+    }
+}
+
+/// Where, and in which format, the host saves the outcome of each job
+#[derive(Clone, Debug)]
+pub struct Output {
+    pub path: String,
+    pub format: OutputFormat,
+}
+
+/// A finished task of the last round of a job
+pub struct TaskReport {
+    pub task: usize,
+    pub participant: String,
+    pub duration: Option<Duration>,
+    pub result: Option<SerdeLuaTable>,
+}
+
+/// Everything saved about a finished job
+pub struct JobReport {
+    pub script: String,
+    pub started: DateTime<Utc>,
+    pub finished: DateTime<Utc>,
+    pub iterations: usize,
+    pub participants: Vec<String>,
+    pub result: String,
+    pub tasks: Vec<TaskReport>,
+}
+
+impl Output {
+    pub fn write(&self, report: &JobReport) -> Result<(), String> {
+        let contents = match self.format {
+            OutputFormat::Json => serde_json::to_string_pretty(&report_to_json(report)).unwrap(),
+            OutputFormat::Csv => report_to_csv(report),
+        };
+
+        let mut file = File::create(&self.path).map_err(|e| e.to_string())?;
+
+        file.write_all(contents.as_bytes()).map_err(|e| e.to_string())
+    }
+}
+
+fn key_to_string(key: &SerdeLuaValue) -> String {
+    match key {
+        SerdeLuaValue::String(string) => string.clone(),
+        SerdeLuaValue::Number(number) => format!("{}", number),
+        SerdeLuaValue::Boolean(boolean) => format!("{}", boolean),
+        SerdeLuaValue::Table(_) => unreachable!("Tables cannot be used as keys")
+    }
+}
+
+fn value_to_json(value: &SerdeLuaValue) -> Value {
+    match value {
+        SerdeLuaValue::String(string) => Value::from(string.as_str()),
+        SerdeLuaValue::Number(number) => Value::from(*number),
+        SerdeLuaValue::Boolean(boolean) => Value::from(*boolean),
+        SerdeLuaValue::Table(table) => table_to_json(table),
+    }
+}
+
+// Tables whose keys are exactly 1 to n become JSON arrays, all others become objects
+fn table_to_json(table: &SerdeLuaTable) -> Value {
+    let mut indices: Vec<usize> = table.iter()
+        .filter_map(|(key, _)| match key {
+            SerdeLuaValue::Number(index) if index.fract() == 0.0 && *index >= 1.0 => Some(*index as usize),
+            _ => None
+        })
+        .collect();
+
+    indices.sort_unstable();
+
+    let is_array = !table.is_empty() && indices.len() == table.len() && indices.iter().enumerate().all(|(i, index)| *index == i + 1);
+
+    if is_array {
+        let mut items: Vec<_> = table.iter()
+            .map(|(key, value)| match key {
+                SerdeLuaValue::Number(index) => (*index as usize, value_to_json(value)),
+                _ => unreachable!()
+            })
+            .collect();
+
+        items.sort_by_key(|(index, _)| *index);
+
+        Value::Array(items.into_iter().map(|(_, value)| value).collect())
+    } else {
+        Value::Object(table.iter()
+            .map(|(key, value)| (key_to_string(key), value_to_json(value)))
+            .collect::<Map<_, _>>())
+    }
+}
+
+fn report_to_json(report: &JobReport) -> Value {
+    let tasks: Vec<_> = report.tasks.iter()
+        .map(|task| json!({
+            "task": task.task,
+            "participant": task.participant,
+            "duration": task.duration.map(|duration| duration.as_secs_f64()),
+            "result": task.result.as_ref().map(table_to_json),
+        }))
+        .collect();
+
+    json!({
+        "script": report.script,
+        "started": report.started.to_rfc3339(),
+        "finished": report.finished.to_rfc3339(),
+        "duration": (report.finished - report.started).num_milliseconds() as f64 / 1000.0,
+        "iterations": report.iterations,
+        "participants": report.participants,
+        "result": report.result,
+        "tasks": tasks,
+    })
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        String::from(field)
+    }
+}
+
+// One row per task, with a column for each top level key found in the result tables. Nested tables
+// are written as JSON, and the job metadata is written as `#` comment lines before the header
+fn report_to_csv(report: &JobReport) -> String {
+    let mut csv = String::new();
+
+    let metadata = [
+        ("script", report.script.clone()),
+        ("started", report.started.to_rfc3339()),
+        ("finished", report.finished.to_rfc3339()),
+        ("iterations", format!("{}", report.iterations)),
+        ("participants", report.participants.join(";")),
+        ("result", report.result.clone()),
+    ];
+
+    for (name, value) in metadata.iter() {
+        csv.push_str(format!("# {}: {}\n", name, value.replace('\r', "\\r").replace('\n', "\\n")).as_str());
+    }
+
+    //Columns are kept in the order their keys first appear
+    let mut columns: Vec<String> = Vec::new();
+
+    for task in report.tasks.iter() {
+        if let Some(result) = &task.result {
+            for (key, _) in result.iter() {
+                let column = key_to_string(key);
+
+                if !columns.contains(&column) {
+                    columns.push(column);
+                }
+            }
+        }
+    }
+
+    let mut header = vec![String::from("task"), String::from("participant"), String::from("duration")];
+    header.extend(columns.iter().map(|column| escape_csv(column.as_str())));
+
+    csv.push_str(header.join(",").as_str());
+    csv.push('\n');
+
+    for task in report.tasks.iter() {
+        let mut row = vec![
+            format!("{}", task.task),
+            escape_csv(task.participant.as_str()),
+            task.duration.map_or(String::new(), |duration| format!("{}", duration.as_secs_f64())),
+        ];
+
+        for column in columns.iter() {
+            let value = task.result.as_ref()
+                .and_then(|result| result.iter().find(|(key, _)| key_to_string(key) == *column))
+                .map(|(_, value)| value);
+
+            row.push(match value {
+                Some(SerdeLuaValue::String(string)) => escape_csv(string.as_str()),
+                Some(SerdeLuaValue::Number(number)) => format!("{}", number),
+                Some(SerdeLuaValue::Boolean(boolean)) => format!("{}", boolean),
+                Some(SerdeLuaValue::Table(table)) => escape_csv(table_to_json(table).to_string().as_str()),
+                None => String::new(),
+            });
+        }
+
+        csv.push_str(row.join(",").as_str());
+        csv.push('\n');
+    }
+
+    csv
+}
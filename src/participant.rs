@@ -1,279 +1,488 @@
-use hlua::{AnyLuaValue, Lua, LuaTable};
-use message_io::network::{NetEvent, Network, Transport};
+use hlua::Lua;
+use message_io::network::{Endpoint, NetEvent, Network, Transport};
 
-use crate::messages::Message;
+use crate::messages::{Message, Registration, HEARTBEAT_INTERVAL};
+use crate::auth;
+use crate::tls;
+use crate::tls::ClientTls;
+use crate::signing::TrustedKeys;
+use crate::sandbox::SandboxProfile;
+use crate::limits::Limits;
+use crate::slot::{Slot, SlotEvent, SlotSender};
+use crate::discovery;
 
-use crossbeam_channel::{Sender, Receiver, unbounded, RecvTimeoutError};
+use crossbeam_channel::{Sender, Receiver, unbounded, bounded, select, RecvTimeoutError};
 
 use std::thread;
-use std::time::Duration;
+use std::thread::JoinHandle;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Delay before the first attempt to reconnect to the host, doubled after each failed attempt up to
+// `RECONNECT_DELAY_MAX`
+const RECONNECT_DELAY_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(30);
+
+// How long to wait for our TLS forwarder to reach message-io
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Tells the participant that a slot has stopped when the slot's thread ends, even if it panicked
+struct SlotExit {
+    slot: usize,
+    exits: Sender<usize>,
+}
+
+impl Drop for SlotExit {
+    fn drop(&mut self) {
+        let _ = self.exits.send(self.slot);
+    }
+}
 
-pub struct Participant<'a> {
+/// Where a participant finds the host
+pub enum HostAddress {
+    //A socket address or host:port, resolved on every attempt to connect
+    Fixed(String),
+
+    //Found through the host's announcements on the local network, optionally only for the given job
+    Discover(Option<String>),
+}
+
+/// Reason a participant stopped ticking
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Exit {
+    Killed,
+    Rejected,
+}
 
+/// A participant process. It holds the single connection to the host, and runs each task it is given
+/// on one of its worker slots
+pub struct Participant {
+
+    //Messages for the host. The channel outlives each connection, so the slots holding a sender keep
+    //working after we reconnect
     network: Sender<Message>,
+    net_receiver: Receiver<Message>,
 
-    //message_sender: Sender<NetEvent<Message>>,
+    message_sender: Sender<NetEvent<Message>>,
     message_receiver: Receiver<NetEvent<Message>>,
 
-    lua: Lua<'a>,
+    //Dropping the sender stops the thread forwarding `network` to the current connection
+    connection: Option<(Sender<()>, JoinHandle<()>)>,
+
+    //Endpoint of the current connection to the host, events from any other are ignored
+    host: Option<Endpoint>,
+
+    name: String,
+    server_address: HostAddress,
+    threads: usize,
+    tls: Option<ClientTls>,
+    lua_version: String,
+
+    //Token the host gave us when we last registered, and whether a slot has lost a task to a dropped connection since
+    session: Option<Vec<u8>>,
+    task_lost: Arc<AtomicBool>,
+
+    //Heartbeats are only sent once the host has accepted us, by a thread that stops when sent a value
+    registered: Arc<AtomicBool>,
+    heartbeat: Sender<()>,
+
+    //Events for each slot, `None` once the slot has stopped. Each slot reports on `exits` when it stops
+    slots: Vec<Option<Sender<SlotEvent>>>,
+    exits: Receiver<usize>,
+
+    //Shared secret the host must prove it knows before we run anything it sends, along with the
+    //challenge we sent it
+    secret: Option<Vec<u8>>,
+    challenge: Option<Vec<u8>>,
+    authenticated: bool,
+
+    //Keys the script must be signed with
+    trusted_keys: Option<TrustedKeys>,
 }
 
-impl<'a> Drop for Participant<'a> {
+impl Drop for Participant {
     fn drop(&mut self) {
-
+        let _ = self.heartbeat.try_send(());
     }
 }
 
-impl<'a> Participant<'a> {
+impl Participant {
 
-    pub fn new(name: String, server_address: &str) -> Result<Self, ()> {
+    pub fn new(name: String, server_address: HostAddress, threads: usize, secret: Option<Vec<u8>>, tls: Option<ClientTls>, trusted_keys: Option<TrustedKeys>, sandbox: Option<SandboxProfile>, local_limits: Limits) -> Self {
 
         let (message_sender, message_receiver) = unbounded();
 
-        let mut lua = Lua::new();
+        let (net_sender, net_receiver) = unbounded();
 
-        lua.openlibs();
+        //Each slot has its own interpreter, so ask a throwaway one which version of Lua we run
+        let lua_version = {
+            let mut lua = Lua::new();
+            lua.open_base();
+            lua.get::<String, _>("_VERSION").unwrap_or_default()
+        };
 
-        let (net_sender, net_receiver) = unbounded();
+        let task_lost = Arc::new(AtomicBool::new(false));
+
+        let (exit_sender, exits) = unbounded();
+
+        //Lua interpreters cannot be moved between threads, so each slot creates its own on the thread it runs on
+        let slots = (0..threads).map(|index| {
+            let (event_sender, events) = unbounded();
+
+            let slot_sender = SlotSender::new(index, net_sender.clone());
+            let sandbox = sandbox.clone();
+            let task_lost = task_lost.clone();
+            let exit = SlotExit { slot: index, exits: exit_sender.clone() };
+            let signed_only = trusted_keys.is_some();
+
+            thread::spawn(move || {
+                let _exit = exit;
 
-        let network_sender = message_sender.clone();
+                match Slot::new(slot_sender, events, sandbox, local_limits, task_lost, signed_only) {
+                    Ok(mut slot) => slot.run(),
+                    Err(e) => println!("Could not start slot {} - {}", index, e),
+                }
+            });
+
+            Some(event_sender)
+        }).collect();
+
+        let registered = Arc::new(AtomicBool::new(false));
+        let (heartbeat, heartbeat_receiver) = bounded::<()>(1);
+
+        let heartbeat_sender = net_sender.clone();
+        let heartbeat_registered = registered.clone();
+
+        //Runs outside the Lua interpreters, so the host hears from us even while a task is not calling `_check`
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = heartbeat_receiver.recv_timeout(HEARTBEAT_INTERVAL) {
+                if heartbeat_registered.load(Ordering::Relaxed) {
+                    let _ = heartbeat_sender.send(Message::Heartbeat);
+                }
+            }
+        });
+
+        let mut participant = Participant {
+            network: net_sender,
+            net_receiver,
+            message_sender,
+            message_receiver,
+            connection: None,
+            host: None,
+            name,
+            server_address,
+            threads,
+            tls,
+            lua_version,
+            session: None,
+            task_lost,
+            registered,
+            heartbeat,
+            slots,
+            exits,
+            authenticated: false,
+            secret,
+            challenge: None,
+            trusted_keys,
+        };
+
+        participant.connect();
+
+        participant
+    }
+
+    // Makes a single attempt to connect to the host, and registers if it succeeds
+    fn try_connect(& mut self) -> Result<(), ()> {
+        //Nothing but our registration may reach the host until it has accepted it
+        self.registered.store(false, Ordering::Relaxed);
+
+        let network_sender = self.message_sender.clone();
 
         let mut network = Network::new(move |net_event| network_sender.send(net_event).unwrap());
 
-        match network.connect(Transport::Tcp, server_address) {
-            Ok(host_endpoint) => {
-                println!("Participant '{}' connected to host ({})", name, server_address);
+        //The address is resolved on every attempt, as a host found by name or discovered may have moved
+        let addresses: Vec<SocketAddr> = match &self.server_address {
+            HostAddress::Fixed(address) => match address.to_socket_addrs() {
+                Ok(addresses) => addresses.collect(),
+                Err(_e) => return Err(())
+            },
+            HostAddress::Discover(job) => {
+                println!("Looking for a host on the local network...");
+
+                match discovery::discover(job.as_deref()) {
+                    Ok(address) => vec![address],
+                    Err(e) => {
+                        println!("Could not look for a host - {}", e);
+                        return Err(());
+                    }
+                }
+            }
+        };
+
+        //With TLS, our forwarder connects to message-io on the loopback interface, so it is the only
+        //connection we can be sure of
+        let listener = match &self.tls {
+            Some(_) => match network.listen(Transport::Tcp, "127.0.0.1:0") {
+                Ok(listener) => Some(listener),
+                Err(_e) => return Err(())
+            },
+            None => None
+        };
+
+        let message_receiver = &self.message_receiver;
+
+        //Try each address the host name resolved to until one accepts us
+        let connection = addresses.into_iter().find_map(|address| {
+            let endpoint = match (&self.tls, &listener) {
+                (Some(tls), Some((_, internal))) => {
+                    let on_error: tls::ErrorHandler = Arc::new(|peer, e| println!("TLS connection to {} failed - {}", peer, e));
+
+                    let tunnel = tls.connect(address, *internal, on_error).ok()?;
 
-                // The following thread monitors the net_sender/net_receiver channel and sends any data
-                // it receives accross the network. This allows us to have multiple senders to the network
-                thread::spawn(move ||
-                    {
+                    accept_tunnel(message_receiver, &mut network, tunnel)?
+                },
+                _ => network.connect(Transport::Tcp, address).ok()?
+            };
 
+            Some((endpoint, address))
+        });
 
-                        loop {
-                            match net_receiver.recv() {
-                                Ok(message) => {
-                                    network.send(host_endpoint, message);
-                                }
-                                Err(_e) => {
-                                    break;
-                                }
+        //Nobody else may connect once our forwarder has
+        if let Some((listener_id, _)) = listener {
+            network.remove_resource(listener_id);
+        }
+
+        let (host_endpoint, address) = match connection {
+            Some(connection) => connection,
+            None => return Err(())
+        };
+
+        println!("Participant '{}' connected to host ({})", self.name, address);
+
+        self.host = Some(host_endpoint);
+
+        //Anything queued while we were disconnected belongs to the old connection, and the host
+        //would refuse it before we register
+        while self.net_receiver.try_recv().is_ok() {}
+
+        // The following thread monitors the net_sender/net_receiver channel and sends any data
+        // it receives accross the network. This allows us to have multiple senders to the network
+        let net_receiver = self.net_receiver.clone();
+        let (stop_sender, stop_receiver) = bounded::<()>(0);
+
+        let handle = thread::spawn(move ||
+            {
+                loop {
+                    select! {
+                        recv(net_receiver) -> message => match message {
+                            Ok(message) => {
+                                network.send(host_endpoint, message);
                             }
+                            Err(_e) => {
+                                break;
+                            }
+                        },
+                        recv(stop_receiver) -> _ => {
+                            break;
                         }
                     }
-                );
+                }
+            }
+        );
 
-                // Register the participant
-                net_sender.send(Message::Register(String::from(name))).unwrap();
+        self.connection = Some((stop_sender, handle));
 
-                Ok(Participant {
-                    network: net_sender,
-                    message_receiver,
-                    lua
-                })
-            }
-            Err(_e) => {
-                Err(())
-                //panic!("Could not connect to {} - {}", server_address, e);
-            }
+        // Register the participant, telling the host which version we are so it can refuse us if
+        // we are not compatible
+        self.challenge = self.secret.as_ref().map(|_| auth::new_challenge());
+        self.authenticated = self.secret.is_none();
+
+        self.network.send(Message::Register(Registration::new(self.name.clone(), self.threads, self.lua_version.clone(), self.challenge.clone(), self.session.clone()))).unwrap();
+
+        if self.authenticated {
+            self.on_registered();
         }
 
+        Ok(())
+    }
+
+    // Connects to the host, waiting longer after each failed attempt
+    fn connect(& mut self) {
+        let mut delay = RECONNECT_DELAY_MIN;
+
+        println!("Searching for host...");
+
+        while let Err(_) = self.try_connect() {
+            thread::sleep(delay);
+
+            delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+        }
+    }
+
+    // Closes the current connection and connects to the host again
+    fn reconnect(& mut self) {
+        if let Some((stop_sender, handle)) = self.connection.take() {
+            drop(stop_sender);
+            let _ = handle.join();
+        }
+
+        self.connect();
+    }
+
+    // Called once the host has accepted our registration
+    fn on_registered(& mut self) {
+        self.registered.store(true, Ordering::Relaxed);
 
+        if self.task_lost.swap(false, Ordering::Relaxed) {
+            self.network.send(Message::ParticipantWarning(String::from("Lost the connection to the host while running a task, its result was discarded."))).unwrap();
+        }
+
+        //We register with every slot, so tell the host again which ones have stopped
+        for (slot, _) in self.slots.iter().enumerate().filter(|(_, events)| events.is_none()) {
+            self.network.send(Message::Slot(slot, Box::new(Message::Unregister))).unwrap();
+        }
     }
 
-    fn recv_message(rec: & Receiver<NetEvent<Message>>, dur: Option<u64>) -> Option<Message> {
-        let net_event = if let Some(duration) = dur {
-            match rec.recv_timeout(Duration::from_micros(duration)) {
-                Ok(msg) => {
-                    msg
+    // Handles a message from a host that has not yet proven it knows the shared secret
+    fn authenticate(& mut self, message: Message) -> Result<(), Exit> {
+        match message {
+            Message::Challenge(host_challenge, response) => {
+                let secret = self.secret.as_ref().unwrap();
+
+                if auth::verify(secret, auth::HOST_ROLE, self.challenge.as_ref().unwrap(), &response) {
+                    self.network.send(Message::ChallengeResponse(auth::respond(secret, auth::PARTICIPANT_ROLE, &host_challenge))).unwrap();
+                    self.authenticated = true;
+                    self.on_registered();
+                    Ok(())
+                } else {
+                    println!("Host failed authentication, disconnecting.");
+                    Err(Exit::Rejected)
                 }
-                Err(receive_error) => match receive_error {
-                    RecvTimeoutError::Disconnected => {
-                        panic!("Participant::message_receiver has disconnected")
-                    },
-                    RecvTimeoutError::Timeout => {
-                        return None;
+            },
+            Message::Rejected(reason) => {
+                println!("Rejected by host - {}", reason);
+                Err(Exit::Rejected)
+            },
+            _ => {
+                println!("Host did not authenticate, disconnecting.");
+                Err(Exit::Rejected)
+            }
+        }
+    }
+
+    // Passes an event to every slot that is still running
+    fn broadcast<F: Fn() -> SlotEvent>(& self, event: F) {
+        for slot in self.slots.iter().flatten() {
+            let _ = slot.send(event());
+        }
+    }
+
+    // Handles a message for the whole participant, passing on anything meant for its slots
+    fn host_message(& mut self, message: Message) -> Result<(), Exit> {
+        //Nothing from the host is trusted until it has answered our challenge
+        if !self.authenticated {
+            return self.authenticate(message);
+        }
+
+        match message {
+            Message::Code(code, entry_points, signature) => {
+                //Only run scripts signed by a key we trust, if we have been given any. The slots
+                //refuse tasks until a trusted script arrives
+                if let Some(trusted_keys) = &self.trusted_keys {
+                    if let Err(e) = trusted_keys.verify(code.as_str(), &entry_points, signature.as_deref()) {
+                        println!("Refused to run script - {}", e);
+                        self.network.send(Message::ParticipantError(format!("Refused to run script - {}", e))).unwrap();
+                        self.broadcast(|| SlotEvent::Untrusted);
+                        return Ok(());
                     }
                 }
-            }
-        } else {
-            rec.recv().expect("Participant::message_receiver has disconnected")
-        };
 
-        match net_event {
-            NetEvent::Message(_, message) => {
-                Some(message)
+                self.broadcast(|| SlotEvent::Message(Message::Code(code.clone(), entry_points.clone(), signature.clone())));
             },
-            NetEvent::RemovedEndpoint(_endpoint) => {
-                println!("Server Disconnected. See Host for more details.");
-                std::process::exit(0);
-            }
+            Message::Limits(limits) => {
+                self.broadcast(|| SlotEvent::Message(Message::Limits(limits)));
+            },
+            Message::Registered(session) => {
+                self.session = Some(session);
+            },
+            Message::Slot(slot, message) => {
+                match self.slots.get(slot) {
+                    Some(Some(events)) => {
+                        let _ = events.send(SlotEvent::Message(*message));
+                    },
+                    //The slot has stopped, but the host may not have heard yet
+                    Some(None) => {
+                        self.network.send(Message::Slot(slot, Box::new(Message::Unregister))).unwrap();
+                    },
+                    None => {
+                        println!("Host sent a message for slot {}, but there are only {} slots.", slot, self.threads);
+                    }
+                }
+            },
+            Message::Rejected(reason) => {
+                println!("Rejected by host - {}", reason);
+                return Err(Exit::Rejected);
+            },
+
             _ => {
-                None
+                self.network.send(Message::ParticipantError(format!("Invalid message {:?}", message))).unwrap();
+                panic!("Invalid message {:?}", message);
             }
         }
+
+        Ok(())
     }
 
-    pub fn tick(& mut self) -> Result<(), ()> {
+    // Called when a slot's thread has ended, after the host killed it or its script failed
+    fn slot_exited(& mut self, slot: usize) -> Result<(), Exit> {
+        println!("Slot {} has stopped.", slot);
+
+        self.slots[slot] = None;
+
+        //The host stops giving the slot tasks
+        self.network.send(Message::Slot(slot, Box::new(Message::Unregister))).unwrap();
+
+        //Slots only stop when killed, or if they could not be started
+        if self.slots.iter().all(|slot| slot.is_none()) {
+            return Err(Exit::Killed);
+        }
+
+        Ok(())
+    }
 
+    pub fn tick(& mut self) -> Result<(), Exit> {
+        let message_receiver = self.message_receiver.clone();
+        let exits = self.exits.clone();
 
-        match self.message_receiver.recv() {
-            Ok(nevent) => match nevent {
+        select! {
+            recv(message_receiver) -> nevent => match nevent {
+                Ok(nevent) => match nevent {
+                    //Left over from an earlier connection
+                    NetEvent::Message(endpoint, _) | NetEvent::RemovedEndpoint(endpoint) | NetEvent::DeserializationError(endpoint) if self.host != Some(endpoint) => {},
                     NetEvent::Message(_, message) => {
-                        match message {
-                            Message::Code(code) => {
-
-
-
-                                let net_sender = self.network.clone();
-
-                                self.lua.set("_print", hlua::function1(move |message: String| {
-                                    net_sender.send(Message::Stdout(message)).unwrap();
-                                }));
-
-                                //Register the _check function which allows Lua script users to check the
-                                //network and respond to pause/play and stop commands
-                                let receiver = self.message_receiver.clone();
-                                let net_sender = self.network.clone();
-
-
-
-                                self.lua.set("_check", hlua::function0(move ||
-                                    {
-                                        let refy = & receiver;
-
-
-                                        //println!("Check start");
-                                        match Self::recv_message(refy, Some(0)) {
-                                            Some(msg) => match msg {
-                                                Message::Kill => {
-                                                    std::process::exit(0);
-                                                    //panic!("This is a cheaty way to kill the thread, but fuck it, we'll do it live!");
-                                                }
-                                                Message::Pause => {
-
-                                                    net_sender.send(Message::Paused).unwrap();
-                                                    loop {
-                                                        match Self::recv_message(refy, None) {
-                                                            Some(ms) => match ms {
-                                                                Message::Kill => {
-                                                                    std::process::exit(0);
-                                                                    //panic!("This is a cheaty way to kill the thread, but fuck it, we'll do it live!");
-                                                                }
-                                                                Message::Play => {
-
-                                                                    net_sender.send(Message::Executing).unwrap();
-                                                                    break;
-                                                                }
-                                                                _ => {
-                                                                    println!("Inner: {:?}", msg);
-                                                                }
-                                                            }
-                                                            None => {
-
-                                                            }
-                                                        }
-                                                    }
-
-                                                }
-                                                _ => {
-                                                    println!("MESSAGE RECEIVED DURING PAUSE Check: {:?}", msg);
-                                                }
-                                            }
-                                            None => {
-
-                                            }
-                                        }
-                                        //println!("Check finish");
-                                    }
-                                ));
-
-                                //Register the _progress function which allows Lua script users to send
-                                //data back to the host indicating how much progress the script has made
-                                let net_sender = self.network.clone();
-
-                                let mut last_progress_update = std::time::Instant::now();
-
-                                self.lua.set("_progress", hlua::function2(move |prog: f32, delay: u32|
-                                {
-                                    if std::time::Instant::now().duration_since(last_progress_update).as_millis() > delay as u128 {
-                                        net_sender.send(Message::Progress(prog)).unwrap();
-                                        last_progress_update = std::time::Instant::now();
-                                    }
-
-                                }));
-
-
-                                match self.lua.execute::<()>(code.as_str()) {
-                                    Ok(_) => {}
-                                    Err(e) => {
-                                        self.network.send( Message::ParticipantError(String::from(format!("LuaError on receive Message::Code - {:?}", e)))).unwrap();
-                                        panic!("LuaError on receive Message::Code - {:?}", e);
-                                    }
-                                }
-                            },
-                            Message::VectorHTP(data) => {
-                                let mut arr = self.lua.empty_array("global_data");
-
-
-                                for (_, value) in data.iter().enumerate() {
-                                    arr.set(value.0.clone(), value.1.clone());
-                                }
-                            },
-                            Message::Pause => {},
-                            Message::Play => {},
-                            Message::Stop => {},
-                            Message::Kill => {
-                                std::process::exit(0);
-                                //panic!("This is a cheaty way to kill the thread, but fuck it, we'll do it live!");
-                            },
-                            Message::Execute => {
-
-                                match self.lua.get::<hlua::LuaFunction<_>, _>("execute_code")
-                                {
-                                    Some(mut generate_data) => {
-
-                                        self.network.send(Message::Executing).unwrap();
-
-                                        match generate_data.call::<LuaTable<_>>() {
-                                            Ok(mut result) => {
-                                                let list: crate::lua::SerdeLuaTable = result.iter::<AnyLuaValue, AnyLuaValue>().map(|pair| pair.unwrap()).collect();
-
-                                                self.network.send( Message::VectorPTH(list)).unwrap();
-                                                //result
-                                            }
-                                            Err(e) => {
-                                                self.network.send(Message::ParticipantError(String::from(format!("LuaError on receive Message::Execute (Lua function return type) - {:?}", e)))).unwrap();
-                                                panic!("LuaError on receive Message::Execute - {:?}", e);
-                                            }
-                                        };
-
-
-
-                                    },
-                                    None => {
-                                        self.network.send( Message::ParticipantError(String::from("LuaError on receive Message::Execute (Lua function call) - Function 'execute_code' does not exist."))).unwrap();
-                                        panic!("LuaError on receive Message::Execute - Function 'execute_code' does not exist.");
-                                    }
-                                }
-                            },
-
-                            _ => {
-                                self.network.send(Message::ParticipantError(format!("Invalid message {:?}", message))).unwrap();
-                                panic!("Invalid message {:?}", message);
-                            }
-                        }
+                        return self.host_message(message);
                     }
                     NetEvent::AddedEndpoint(_endpoint) => {},
                     NetEvent::RemovedEndpoint(_endpoint) => {
-                        println!("Server Disconnected. See Host for more details.");
-                        return Err(())
-                    }
-                    NetEvent::DeserializationError(_) => (),
-
-            }
-            Err(_) => {
+                        println!("Lost connection to host, reconnecting.");
 
+                        //Running tasks are given to other participants, so the slots give them up
+                        self.broadcast(|| SlotEvent::Disconnected);
+                        self.reconnect();
+                    }
+                    NetEvent::DeserializationError(_) => {
+                        println!("Could not read message from host, it may be running an incompatible version of midas.");
+                    },
+                },
+                Err(_) => {}
+            },
+            recv(exits) -> slot => match slot {
+                Ok(slot) => {
+                    return self.slot_exited(slot);
+                },
+                Err(_) => {}
             }
         }
 
@@ -281,3 +490,23 @@ impl<'a> Participant<'a> {
     }
 
 }
+
+// Waits for our TLS forwarder, connecting from `tunnel`, to reach the loopback listener, dropping any
+// other connection made to it in the meantime
+fn accept_tunnel(events: &Receiver<NetEvent<Message>>, network: &mut Network, tunnel: SocketAddr) -> Option<Endpoint> {
+    let deadline = Instant::now() + TUNNEL_TIMEOUT;
+
+    loop {
+        let event = events.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok()?;
+
+        match event {
+            NetEvent::AddedEndpoint(endpoint) if endpoint.addr() == tunnel => return Some(endpoint),
+            NetEvent::AddedEndpoint(endpoint) => {
+                println!("Dropped local connection from {}, which is not our TLS forwarder", endpoint.addr());
+                network.remove_resource(endpoint.resource_id());
+            },
+            //The host does not speak before we register, so anything else is from an earlier connection
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use hlua::{Lua, LuaError};
+
+use std::path::{Component, Path, PathBuf};
+
+// Removes the parts of the base and string libraries that can load code from files or bytecode,
+// and cuts `os` down to the timing functions
+const RESTRICT: &str = r#"
+dofile = nil
+loadfile = nil
+load = nil
+loadstring = nil
+string.dump = nil
+
+if os then
+    os = { time = os.time, clock = os.clock }
+end
+"#;
+
+// Lua sides of the file functions. Rust callbacks cannot raise Lua errors, so each `_midas_*_file`
+// callback returns whether it succeeded along with its result or error message, and the error is
+// raised here.
+const READ_FILE: &str = r#"
+function _read_file(path)
+    local ok, result = _midas_read_file(path)
+    if not ok then error(result, 2) end
+    return result
+end
+"#;
+
+const WRITE_FILE: &str = r#"
+function _write_file(path, contents)
+    local ok, result = _midas_write_file(path, contents, false)
+    if not ok then error(result, 2) end
+end
+
+function _append_file(path, contents)
+    local ok, result = _midas_write_file(path, contents, true)
+    if not ok then error(result, 2) end
+end
+"#;
+
+/// What a sandboxed participant's scripts may use beyond the math, string and table libraries
+#[derive(Clone, Debug, Default)]
+pub struct SandboxProfile {
+    /// Directory that `_read_file` may read from. Without one, scripts cannot read files
+    pub read_directory: Option<PathBuf>,
+
+    /// Whether `os.time` and `os.clock` are available
+    pub allow_time: bool,
+}
+
+/// Resolves `path` inside `directory`, refusing absolute paths and paths that leave the directory,
+/// including through symbolic links
+pub fn sandboxed_path(directory: &Path, path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+
+    let outside = || format!("`{}` is not inside {}", path, directory.display());
+
+    if relative.components().any(|component| !matches!(component, Component::Normal(_) | Component::CurDir)) {
+        return Err(outside());
+    }
+
+    let root = directory.canonicalize().map_err(|e| format!("Could not open {} - {}", directory.display(), e))?;
+
+    //Symbolic links could lead anywhere, so find where the part of the path that exists really is.
+    //Anything missing is created inside it
+    let mut existing = directory.join(relative);
+    let mut missing = Vec::new();
+
+    while existing.symlink_metadata().is_err() {
+        match (existing.file_name().map(|name| name.to_owned()), existing.parent().map(|parent| parent.to_path_buf())) {
+            (Some(name), Some(parent)) => {
+                missing.push(name);
+                existing = parent;
+            },
+            _ => return Err(outside())
+        }
+    }
+
+    let real = existing.canonicalize().map_err(|e| e.to_string())?;
+
+    if !real.starts_with(&root) {
+        return Err(outside());
+    }
+
+    Ok(missing.into_iter().rev().fold(real, |path, name| path.join(name)))
+}
+
+/// Registers `_read_file(path)`, which reads files inside `directory`
+pub fn register_read_file(lua: &mut Lua, directory: PathBuf) -> Result<(), LuaError> {
+    lua.set("_midas_read_file", hlua::function1(move |path: String| -> (bool, String) {
+        match sandboxed_path(&directory, path.as_str()).and_then(|path| std::fs::read_to_string(path).map_err(|e| e.to_string())) {
+            Ok(contents) => (true, contents),
+            Err(e) => (false, format!("Could not read `{}` - {}", path, e)),
+        }
+    }));
+
+    lua.execute::<()>(READ_FILE)
+}
+
+/// Registers `_write_file(path, contents)` and `_append_file(path, contents)`, which write files
+/// inside `directory`, creating any missing directories on the way
+pub fn register_write_file(lua: &mut Lua, directory: PathBuf) -> Result<(), LuaError> {
+    lua.set("_midas_write_file", hlua::function3(move |path: String, contents: String, append: bool| -> (bool, String) {
+        let result = sandboxed_path(&directory, path.as_str()).and_then(|path| {
+            use std::io::Write;
+
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+
+            std::fs::OpenOptions::new()
+                .create(true)
+                .write(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)
+                .and_then(|mut file| file.write_all(contents.as_bytes()))
+                .map_err(|e| e.to_string())
+        });
+
+        match result {
+            Ok(_) => (true, String::new()),
+            Err(e) => (false, format!("Could not write `{}` - {}", path, e)),
+        }
+    }));
+
+    lua.execute::<()>(WRITE_FILE)
+}
+
+/// Opens the standard libraries, or with a profile only the safe ones and whatever it allows
+pub fn open_libraries(lua: &mut Lua, profile: Option<&SandboxProfile>) -> Result<(), LuaError> {
+    let profile = match profile {
+        Some(profile) => profile,
+        None => {
+            lua.openlibs();
+            return Ok(());
+        }
+    };
+
+    lua.open_base();
+    lua.open_math();
+    lua.open_string();
+    lua.open_table();
+
+    if profile.allow_time {
+        lua.open_os();
+    }
+
+    lua.execute::<()>(RESTRICT)?;
+
+    if let Some(directory) = &profile.read_directory {
+        register_read_file(lua, directory.clone())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    // A fresh directory for one test, with a `root` directory to sandbox and an `outside` one next to it
+    fn scratch(name: &str) -> PathBuf {
+        let scratch = std::env::temp_dir().join(format!("midas-sandbox-{}-{}", name, std::process::id()));
+
+        let _ = fs::remove_dir_all(&scratch);
+        fs::create_dir_all(scratch.join("root")).unwrap();
+        fs::create_dir_all(scratch.join("outside")).unwrap();
+
+        scratch
+    }
+
+    #[test]
+    fn parent_components_are_refused() {
+        let scratch = scratch("parent");
+
+        assert!(sandboxed_path(&scratch.join("root"), "../outside/file.txt").is_err());
+        assert!(sandboxed_path(&scratch.join("root"), "inner/../../outside/file.txt").is_err());
+
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+
+    #[test]
+    fn absolute_paths_are_refused() {
+        let scratch = scratch("absolute");
+        let absolute = scratch.join("root").join("file.txt");
+
+        assert!(sandboxed_path(&scratch.join("root"), absolute.to_str().unwrap()).is_err());
+
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_out_of_the_directory_are_refused() {
+        let scratch = scratch("symlink");
+
+        std::os::unix::fs::symlink(scratch.join("outside"), scratch.join("root").join("link")).unwrap();
+
+        assert!(sandboxed_path(&scratch.join("root"), "link/file.txt").is_err());
+        assert!(sandboxed_path(&scratch.join("root"), "link/missing/file.txt").is_err());
+
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+
+    #[test]
+    fn missing_directories_resolve_inside() {
+        let scratch = scratch("missing");
+        let root = scratch.join("root");
+
+        let path = sandboxed_path(&root, "missing/deeper/file.txt").unwrap();
+
+        assert_eq!(path, root.canonicalize().unwrap().join("missing").join("deeper").join("file.txt"));
+
+        fs::remove_dir_all(&scratch).unwrap();
+    }
+}
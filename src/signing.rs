@@ -0,0 +1,110 @@
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+
+use std::convert::TryFrom;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// What a signature covers: the script and the functions participants may be asked to call, each
+// preceded by its length so that no two scripts produce the same bytes
+fn signed_bytes(code: &str, entry_points: &[String]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    for part in std::iter::once(code).chain(entry_points.iter().map(|name| name.as_str())) {
+        bytes.extend_from_slice(&(part.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(part.as_bytes());
+    }
+
+    bytes
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+// Key files hold one hex encoded key per line. Blank lines and lines starting with `#` are ignored
+fn read_keys(path: &str) -> Result<Vec<Vec<u8>>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Could not read key file ({}) - {}", path, e))?;
+
+    contents.lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| from_hex(line).ok_or_else(|| format!("Invalid hex key in {} ({})", path, line)))
+        .collect()
+}
+
+/// Ed25519 key used by the host to sign the scripts it sends
+pub struct SigningKey {
+    keypair: Keypair,
+}
+
+impl SigningKey {
+    /// Loads the hex encoded 32 byte secret key in `path`
+    pub fn load(path: &str) -> Result<Self, String> {
+        let keys = read_keys(path)?;
+
+        let secret = match keys.first() {
+            Some(key) => SecretKey::from_bytes(key).map_err(|_| format!("Signing key in {} must be 32 bytes", path))?,
+            None => return Err(format!("No signing key found in {}", path))
+        };
+
+        let public = PublicKey::from(&secret);
+
+        Ok(SigningKey {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    /// Hex encoded public key, for participants to add to their trusted keys
+    pub fn public_key(&self) -> String {
+        to_hex(self.keypair.public.as_bytes())
+    }
+
+    /// Signs a script along with the functions participants may be asked to call
+    pub fn sign(&self, code: &str, entry_points: &[String]) -> Vec<u8> {
+        self.keypair.sign(&signed_bytes(code, entry_points)).to_bytes().to_vec()
+    }
+}
+
+/// Public keys a participant accepts scripts from
+#[derive(Clone)]
+pub struct TrustedKeys {
+    keys: Vec<PublicKey>,
+}
+
+impl TrustedKeys {
+    /// Loads the hex encoded public keys in `path`, one per line
+    pub fn load(path: &str) -> Result<Self, String> {
+        let keys = read_keys(path)?.iter()
+            .map(|key| PublicKey::from_bytes(key).map_err(|_| format!("Invalid public key in {} ({})", path, to_hex(key))))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if keys.is_empty() {
+            return Err(format!("No trusted keys found in {}", path));
+        }
+
+        Ok(TrustedKeys { keys })
+    }
+
+    /// Checks that `code` and its entry points were signed by one of the trusted keys
+    pub fn verify(&self, code: &str, entry_points: &[String], signature: Option<&[u8]>) -> Result<(), String> {
+        let signature = match signature {
+            Some(signature) => Signature::try_from(signature).map_err(|_| String::from("Script signature is malformed"))?,
+            None => return Err(String::from("Script is not signed"))
+        };
+
+        if self.keys.iter().any(|key| key.verify_strict(&signed_bytes(code, entry_points), &signature).is_ok()) {
+            Ok(())
+        } else {
+            Err(String::from("Script is not signed by a trusted key"))
+        }
+    }
+}
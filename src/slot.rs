@@ -0,0 +1,427 @@
+// A participant process runs one `Slot` per worker thread, each with its own Lua interpreter. Slots
+// share the process's connection to the host: messages for a slot arrive from `Participant` as
+// `SlotEvent`s, and everything a slot sends is wrapped in `Message::Slot` so the host knows which
+// slot it came from.
+
+use hlua::{Lua, AnyLuaValue};
+
+use crate::messages::Message;
+use crate::lua;
+use crate::sandbox;
+use crate::sandbox::SandboxProfile;
+use crate::limits;
+use crate::limits::Limits;
+
+use crossbeam_channel::{Sender, Receiver, RecvTimeoutError};
+
+use std::time::Duration;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::cell::{Cell, RefCell};
+
+// Lua side of `_emit` and `_check`. For `_emit` the table is flattened in Lua and its records passed
+// to Rust one at a time, since Rust callbacks cannot read tables from the interpreter that is calling
+// them. As with the file functions in `sandbox`, errors are raised on the Lua side: `_check` raises the
+// one that stops `execute_code` when the host has asked for the task to stop, and `_emit` the one for
+// a table that cannot be sent.
+const HELPERS: &str = r#"
+function _check()
+    if _midas_check() then
+        error("task stopped by host", 0)
+    end
+end
+
+function _emit(t)
+    _midas_flatten(t)
+
+    if _midas_flat == nil then return end
+
+    for i = 1, #_midas_flat do
+        _midas_emit_value(_midas_flat[i])
+    end
+
+    _midas_flat = nil
+
+    local ok, message = _midas_emit_end()
+    if not ok then error(message, 2) end
+end
+"#;
+
+/// What `Participant` passes on to a slot
+pub enum SlotEvent {
+    Message(Message),
+
+    //The last script was not signed by a trusted key, so tasks are refused until the next one
+    Untrusted,
+
+    //The connection to the host dropped. Participant reconnects, the slot only has to give up its task
+    Disconnected,
+}
+
+/// Sends messages to the host on behalf of a slot
+#[derive(Clone)]
+pub struct SlotSender {
+    slot: usize,
+    sender: Sender<Message>,
+}
+
+impl SlotSender {
+    pub fn new(slot: usize, sender: Sender<Message>) -> Self {
+        SlotSender { slot, sender }
+    }
+
+    pub fn send(&self, message: Message) {
+        //The channel only closes once the participant is exiting
+        let _ = self.sender.send(Message::Slot(self.slot, Box::new(message)));
+    }
+}
+
+// Why `_check` asked `execute_code` to finish early
+#[derive(Clone, Copy, PartialEq)]
+enum Interrupt {
+    None,
+    Stop,
+    Kill,
+    Disconnected,
+}
+
+pub struct Slot<'a> {
+
+    network: SlotSender,
+
+    events: Receiver<SlotEvent>,
+
+    lua: Lua<'a>,
+
+    //Set by `_check` when the host sends `Message::Stop` or `Message::Kill` during execution
+    interrupt: Rc<Cell<Interrupt>>,
+
+    //Whether the last script received was signed by a trusted key, and the functions the signature
+    //covers. Only checked when the participant has trusted keys
+    script_trusted: bool,
+    entry_points: Vec<String>,
+    signed_only: bool,
+
+    //Limits the host asked for, and the caps we put on them
+    limits: Limits,
+    local_limits: Limits,
+
+    //Set when a task is given up because the connection dropped, so the host can be told once we are back
+    task_lost: Arc<AtomicBool>,
+
+    //Set when the data of the next task could not be loaded, so it is not run
+    unreadable_task: bool,
+}
+
+impl<'a> Slot<'a> {
+
+    pub fn new(network: SlotSender, events: Receiver<SlotEvent>, sandbox: Option<SandboxProfile>, local_limits: Limits, task_lost: Arc<AtomicBool>, signed_only: bool) -> Result<Self, String> {
+        let mut lua = Lua::new();
+
+        if let Err(e) = sandbox::open_libraries(&mut lua, sandbox.as_ref()) {
+            return Err(format!("Could not open Lua libraries - {:?}", e));
+        }
+
+        if let Err(e) = lua::load_helpers(&mut lua) {
+            return Err(format!("Could not load Lua helpers - {:?}", e));
+        }
+
+        Ok(Slot {
+            network,
+            events,
+            lua,
+            interrupt: Rc::new(Cell::new(Interrupt::None)),
+            //Nothing has been signed until the first script arrives
+            script_trusted: !signed_only,
+            entry_points: Vec::new(),
+            signed_only,
+            limits: Limits::default(),
+            local_limits,
+            task_lost,
+            unreadable_task: false,
+        })
+    }
+
+    fn recv_message(rec: & Receiver<SlotEvent>, dur: Option<u64>, interrupt: & Cell<Interrupt>) -> Option<Message> {
+        let event = if let Some(duration) = dur {
+            match rec.recv_timeout(Duration::from_micros(duration)) {
+                Ok(event) => {
+                    event
+                }
+                Err(receive_error) => match receive_error {
+                    RecvTimeoutError::Disconnected => {
+                        //The participant is exiting
+                        interrupt.set(Interrupt::Kill);
+                        return None;
+                    },
+                    RecvTimeoutError::Timeout => {
+                        return None;
+                    }
+                }
+            }
+        } else {
+            match rec.recv() {
+                Ok(event) => event,
+                Err(_) => {
+                    interrupt.set(Interrupt::Kill);
+                    return None;
+                }
+            }
+        };
+
+        match event {
+            SlotEvent::Message(message) => {
+                Some(message)
+            },
+            SlotEvent::Disconnected => {
+                //The task is stopped, as the host will give it to someone else
+                println!("Lost connection to host while running a task.");
+                interrupt.set(Interrupt::Disconnected);
+                None
+            }
+            SlotEvent::Untrusted => {
+                None
+            }
+        }
+    }
+
+    // Loads a new script into the interpreter along with the Midas functions it may call
+    fn load_script(& mut self, code: String) {
+        let net_sender = self.network.clone();
+
+        self.lua.set("_print", hlua::function1(move |message: String| {
+            net_sender.send(Message::Stdout(message));
+        }));
+
+        //Register the _check function which allows Lua script users to check the
+        //network and respond to pause/play and stop commands
+        let receiver = self.events.clone();
+        let net_sender = self.network.clone();
+        let interrupt = self.interrupt.clone();
+
+
+
+        self.lua.set("_midas_check", hlua::function0(move ||
+            {
+                let refy = & receiver;
+
+
+                match Self::recv_message(refy, Some(0), &interrupt) {
+                    Some(msg) => match msg {
+                        Message::Kill => {
+                            interrupt.set(Interrupt::Kill);
+                        }
+                        Message::Stop => {
+                            interrupt.set(Interrupt::Stop);
+                        }
+                        Message::Pause => {
+
+                            net_sender.send(Message::Paused);
+                            loop {
+                                match Self::recv_message(refy, None, &interrupt) {
+                                    Some(ms) => match ms {
+                                        Message::Kill => {
+                                            interrupt.set(Interrupt::Kill);
+                                            break;
+                                        }
+                                        Message::Stop => {
+                                            interrupt.set(Interrupt::Stop);
+                                            break;
+                                        }
+                                        Message::Play => {
+
+                                            net_sender.send(Message::Executing);
+                                            break;
+                                        }
+                                        _ => {
+                                            net_sender.send(Message::ParticipantWarning(format!("Ignored {:?} while paused", ms)));
+                                        }
+                                    }
+                                    None if interrupt.get() != Interrupt::None => {
+                                        break;
+                                    }
+                                    None => {
+
+                                    }
+                                }
+                            }
+
+                        }
+                        _ => {
+                            net_sender.send(Message::ParticipantWarning(format!("Ignored {:?} while running a task", msg)));
+                        }
+                    }
+                    None => {
+
+                    }
+                }
+
+                interrupt.get() != Interrupt::None
+            }
+        ));
+
+        //Register the _emit function which allows Lua script users to send intermediate
+        //result tables to the host while execute_code is still running
+        let emitted = Rc::new(RefCell::new(Vec::<AnyLuaValue>::new()));
+
+        let records = emitted.clone();
+
+        self.lua.set("_midas_emit_value", hlua::function1(move |value: AnyLuaValue| {
+            records.borrow_mut().push(value);
+        }));
+
+        let net_sender = self.network.clone();
+
+        self.lua.set("_midas_emit_end", hlua::function0(move || -> (bool, String) {
+            let records = std::mem::take(&mut *emitted.borrow_mut());
+
+            match lua::unflatten(&records) {
+                Ok(table) => {
+                    net_sender.send(Message::Partial(table));
+                    (true, String::new())
+                },
+                Err(e) => (false, e)
+            }
+        }));
+
+        self.lua.execute::<()>(HELPERS).unwrap();
+
+        //Register the _progress function which allows Lua script users to send
+        //data back to the host indicating how much progress the script has made
+        let net_sender = self.network.clone();
+
+        let mut last_progress_update = std::time::Instant::now();
+
+        self.lua.set("_progress", hlua::function2(move |prog: f32, delay: u32|
+        {
+            if std::time::Instant::now().duration_since(last_progress_update).as_millis() > delay as u128 {
+                net_sender.send(Message::Progress(prog));
+                last_progress_update = std::time::Instant::now();
+            }
+
+        }));
+
+
+        match self.lua.execute::<()>(code.as_str()) {
+            Ok(_) => {}
+            Err(e) => {
+                self.network.send( Message::ParticipantError(String::from(format!("LuaError on receive Message::Code - {:?}", e))));
+            }
+        }
+    }
+
+    // Calls `function` for the task the host sent, returning false if the slot was killed
+    fn execute(& mut self, function: String) -> bool {
+        if !self.script_trusted {
+            self.network.send(Message::ParticipantError(format!("Refused to execute '{}' - The script was not signed by a trusted key.", function)));
+            return true;
+        }
+
+        if self.signed_only && !self.entry_points.contains(&function) {
+            self.network.send(Message::ParticipantError(format!("Refused to execute '{}' - The script's signature does not cover it.", function)));
+            return true;
+        }
+
+        if self.lua.get::<hlua::LuaFunction<_>, _>(function.as_str()).is_none() {
+            self.network.send( Message::ParticipantError(format!("LuaError on receive Message::Execute (Lua function call) - Function '{}' does not exist.", function)));
+            return true;
+        }
+
+        self.network.send(Message::Executing);
+
+        self.interrupt.set(Interrupt::None);
+
+        let limits = self.limits.capped_by(&self.local_limits);
+
+        let (result, exceeded) = limits::run_limited(&mut self.lua, limits, |lua| lua::evaluate_table(lua, format!("{}()", function).as_str()));
+
+        match (result, exceeded) {
+            _ if self.interrupt.get() == Interrupt::Disconnected => {
+                //The host gives the task to someone else once it notices we left, and
+                //is told the result was lost once we are back
+                self.interrupt.set(Interrupt::None);
+                self.task_lost.store(true, Ordering::Relaxed);
+            }
+            (Err(_), _) if self.interrupt.get() == Interrupt::Stop => {
+                //Stopped by the host, so go back to waiting for the next task
+                self.interrupt.set(Interrupt::None);
+                self.network.send(Message::Cancelled);
+            }
+            (Err(_), _) if self.interrupt.get() == Interrupt::Kill => {
+                return false;
+            }
+            (_, Some(limit)) => {
+                //Even if the script caught the error, its result cannot be trusted
+                println!("Task exceeded the {} limit of {}.", limit, limit.describe(limits.value_of(limit)));
+                self.network.send(Message::LimitExceeded(limit, limits.value_of(limit)));
+            }
+            (Ok(Some(list)), None) => {
+                self.network.send( Message::VectorPTH(list));
+            }
+            (Ok(None), None) => {
+                self.network.send(Message::ParticipantError(format!("LuaError on receive Message::Execute (Lua function return type) - Function '{}' must return a table.", function)));
+            }
+            (Err(e), None) => {
+                self.network.send(Message::ParticipantError(String::from(format!("LuaError on receive Message::Execute (Lua function return type) - {:?}", e))));
+            }
+        };
+
+        true
+    }
+
+    /// Runs tasks until the host kills the slot or the participant exits
+    pub fn run(& mut self) {
+        while let Ok(event) = self.events.recv() {
+            let message = match event {
+                SlotEvent::Message(message) => message,
+                SlotEvent::Untrusted => {
+                    self.script_trusted = false;
+                    continue;
+                },
+                //Nothing to give up while idle
+                SlotEvent::Disconnected => continue
+            };
+
+            match message {
+                Message::Code(code, entry_points, _signature) => {
+                    //The participant has already checked the signature
+                    self.script_trusted = true;
+                    self.entry_points = entry_points;
+
+                    self.load_script(code);
+                },
+                Message::VectorHTP(data) => {
+                    self.unreadable_task = match lua::set_table(&mut self.lua, "global_data", &data) {
+                        Ok(_) => false,
+                        Err(e) => {
+                            self.network.send( Message::ParticipantError(String::from(format!("LuaError on receive Message::VectorHTP - {:?}", e))));
+                            true
+                        }
+                    };
+                },
+                Message::Limits(limits) => {
+                    self.limits = limits;
+                },
+                Message::Pause => {},
+                Message::Play => {},
+                Message::Stop => {},
+                Message::Kill => {
+                    return;
+                },
+                //The host has already been told why the task failed
+                Message::Execute(_) if self.unreadable_task => {},
+                Message::Execute(function) => {
+                    if !self.execute(function) {
+                        return;
+                    }
+                },
+
+                _ => {
+                    self.network.send(Message::ParticipantError(format!("Invalid message {:?}", message)));
+                }
+            }
+        }
+    }
+
+}
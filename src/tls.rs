@@ -0,0 +1,348 @@
+// message-io only speaks plain TCP, so TLS is added as a tunnel in front of it. The host accepts TLS
+// connections and forwards each one to a message-io listener on the loopback interface, and each
+// participant opens the TLS connection to the host and forwards it to a message-io listener of its
+// own. Any local process can connect to those listeners, so both sides only accept connections from
+// the addresses their forwarders connected from.
+
+use rustls::{Certificate, ClientConfig, ClientSession, NoClientAuth, PrivateKey, RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, ServerSession, Session, TLSError};
+use rustls::internal::pemfile;
+use sha2::{Digest, Sha256};
+use webpki::DNSNameRef;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+/// Called with the address of the other end and a description when a TLS connection fails
+pub type ErrorHandler = Arc<dyn Fn(SocketAddr, String) + Send + Sync>;
+
+/// Maps the address message-io sees for a tunnelled connection to the real address of the peer
+pub type PeerAddresses = Arc<Mutex<HashMap<SocketAddr, SocketAddr>>>;
+
+/// SHA-256 fingerprint of a DER encoded certificate, as colon separated hex
+pub fn fingerprint(certificate: &Certificate) -> String {
+    Sha256::digest(&certificate.0).iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn load_certificates(path: &str) -> Result<Vec<Certificate>, String> {
+    let file = File::open(path).map_err(|e| format!("Could not open certificate file ({}) - {}", path, e))?;
+
+    match pemfile::certs(&mut BufReader::new(file)) {
+        Ok(certificates) if !certificates.is_empty() => Ok(certificates),
+        _ => Err(format!("No PEM certificates found in {}", path))
+    }
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKey, String> {
+    let read_keys = |parse: fn(&mut dyn io::BufRead) -> Result<Vec<PrivateKey>, ()>| -> Result<Vec<PrivateKey>, String> {
+        let file = File::open(path).map_err(|e| format!("Could not open key file ({}) - {}", path, e))?;
+        parse(&mut BufReader::new(file)).map_err(|_| format!("Could not parse key file ({})", path))
+    };
+
+    let mut keys = read_keys(pemfile::pkcs8_private_keys)?;
+
+    if keys.is_empty() {
+        keys = read_keys(pemfile::rsa_private_keys)?;
+    }
+
+    keys.into_iter().next().ok_or_else(|| format!("No PKCS8 or RSA private key found in {}", path))
+}
+
+/// TLS settings of the host
+pub struct ServerTls {
+    config: Arc<ServerConfig>,
+    fingerprint: String,
+}
+
+impl ServerTls {
+    /// Loads a PEM certificate chain and private key
+    pub fn load(certificate_path: &str, key_path: &str) -> Result<Self, String> {
+        let certificates = load_certificates(certificate_path)?;
+        let key = load_private_key(key_path)?;
+
+        let fingerprint = fingerprint(&certificates[0]);
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+
+        config.set_single_cert(certificates, key).map_err(|e| format!("Invalid certificate or key - {}", e))?;
+
+        Ok(ServerTls {
+            config: Arc::new(config),
+            fingerprint,
+        })
+    }
+
+    /// Fingerprint of the host's certificate, which participants can pin
+    pub fn fingerprint(&self) -> &str {
+        self.fingerprint.as_str()
+    }
+
+    /// Accepts TLS connections at `address` and forwards them to the plain listener at `internal`,
+    /// recording the real address of each peer in `peers`
+    pub fn serve(&self, address: SocketAddr, internal: SocketAddr, peers: PeerAddresses, on_error: ErrorHandler) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        let config = self.config.clone();
+
+        let tunnel_peers = peers;
+
+        thread::spawn(move || {
+            for encrypted in listener.incoming() {
+                let encrypted = match encrypted {
+                    Ok(stream) => stream,
+                    Err(_) => continue
+                };
+
+                let peer = match encrypted.peer_addr() {
+                    Ok(peer) => peer,
+                    Err(_) => continue
+                };
+
+                let plain = match TcpStream::connect(internal) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        on_error(peer, format!("Could not forward connection - {}", e));
+                        continue;
+                    }
+                };
+
+                let local = plain.local_addr().unwrap();
+
+                tunnel_peers.lock().unwrap().insert(local, peer);
+
+                let peers = tunnel_peers.clone();
+
+                pipe(ServerSession::new(&config), encrypted, plain, peer, on_error.clone(), move || {
+                    peers.lock().unwrap().remove(&local);
+                });
+            }
+        });
+
+        Ok(())
+    }
+}
+
+// Accepts only the certificate with the given fingerprint, whoever signed it. The handshake still
+// checks that the host holds the certificate's private key
+struct PinnedCertificate {
+    fingerprint: Vec<u8>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(&self, _roots: &RootCertStore, presented_certs: &[Certificate], _dns_name: DNSNameRef, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        match presented_certs.first() {
+            Some(certificate) if Sha256::digest(&certificate.0).as_slice() == self.fingerprint.as_slice() => Ok(ServerCertVerified::assertion()),
+            _ => Err(TLSError::General(String::from("Host certificate does not match the pinned fingerprint")))
+        }
+    }
+}
+
+/// TLS settings of a participant
+#[derive(Clone)]
+pub struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: String,
+}
+
+impl ClientTls {
+    /// Trusts host certificates signed by the CA certificates in `ca_path`, issued for `server_name`
+    pub fn with_ca(ca_path: &str, server_name: &str) -> Result<Self, String> {
+        DNSNameRef::try_from_ascii_str(server_name).map_err(|_| format!("Invalid TLS server name ({})", server_name))?;
+
+        let mut config = ClientConfig::new();
+
+        for certificate in load_certificates(ca_path)? {
+            config.root_store.add(&certificate).map_err(|e| format!("Invalid CA certificate in {} - {:?}", ca_path, e))?;
+        }
+
+        Ok(ClientTls {
+            config: Arc::new(config),
+            server_name: String::from(server_name),
+        })
+    }
+
+    /// Trusts only the host certificate with the given SHA-256 fingerprint (hex, colons optional)
+    pub fn with_fingerprint(fingerprint: &str) -> Result<Self, String> {
+        let hex: String = fingerprint.chars().filter(|c| *c != ':').collect();
+
+        let bytes: Result<Vec<u8>, _> = (0..hex.len())
+            .step_by(2)
+            .map(|i| hex.get(i..i + 2).map_or(Err(()), |byte| u8::from_str_radix(byte, 16).map_err(|_| ())))
+            .collect();
+
+        let fingerprint = match bytes {
+            Ok(bytes) if bytes.len() == 32 => bytes,
+            _ => return Err(format!("Invalid SHA-256 fingerprint ({})", fingerprint))
+        };
+
+        let mut config = ClientConfig::new();
+
+        config.dangerous().set_certificate_verifier(Arc::new(PinnedCertificate { fingerprint }));
+
+        Ok(ClientTls {
+            config: Arc::new(config),
+            //Not checked when the certificate is pinned
+            server_name: String::from("midas"),
+        })
+    }
+
+    /// Opens a TLS connection to the host and forwards it to the plain listener at `internal`,
+    /// returning the address the forwarded connection comes from
+    pub fn connect(&self, address: SocketAddr, internal: SocketAddr, on_error: ErrorHandler) -> io::Result<SocketAddr> {
+        let encrypted = TcpStream::connect(address)?;
+        let peer = encrypted.peer_addr()?;
+
+        let plain = TcpStream::connect(internal)?;
+        let local = plain.local_addr()?;
+
+        let session = ClientSession::new(&self.config, DNSNameRef::try_from_ascii_str(self.server_name.as_str()).unwrap());
+
+        pipe(session, encrypted, plain, peer, on_error, || {});
+
+        Ok(local)
+    }
+}
+
+// Takes anything the session has queued (handshake messages, alerts or encrypted data)
+fn take_tls<S: Session>(session: &mut S) -> io::Result<Vec<u8>> {
+    let mut records = Vec::new();
+
+    while session.wants_write() {
+        session.write_tls(&mut records)?;
+    }
+
+    Ok(records)
+}
+
+// Moves data between the encrypted and plain connections until either side closes, then calls `on_close`.
+// The session is only locked to move data in and out of it, never while writing to a socket, as a
+// peer that stops reading would otherwise keep the other direction from draining. Records are
+// written to the encrypted connection by a single thread, in the order the session produced them
+fn pipe<S, F>(session: S, encrypted: TcpStream, plain: TcpStream, peer: SocketAddr, on_error: ErrorHandler, on_close: F)
+    where S: Session + 'static, F: FnOnce() + Send + 'static {
+
+    let session = Arc::new(Mutex::new(session));
+
+    let streams = (encrypted.try_clone(), plain.try_clone());
+
+    let (mut encrypted_reader, mut plain_writer) = match streams {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return
+    };
+
+    let (mut plain_reader, mut encrypted_writer) = (plain, encrypted);
+
+    //`None` asks the writer to close the encrypted connection
+    let (records, outgoing) = mpsc::channel::<Option<Vec<u8>>>();
+
+    //The client speaks first
+    match take_tls(&mut *session.lock().unwrap()) {
+        Ok(hello) => { let _ = records.send(Some(hello)); },
+        Err(e) => {
+            on_error(peer, e.to_string());
+            return;
+        }
+    }
+
+    //Session -> encrypted
+    thread::spawn(move || {
+        while let Ok(Some(buffer)) = outgoing.recv() {
+            if encrypted_writer.write_all(&buffer).is_err() {
+                break;
+            }
+        }
+
+        let _ = encrypted_writer.shutdown(Shutdown::Both);
+    });
+
+    let incoming_session = session.clone();
+    let incoming_records = records.clone();
+
+    //Encrypted -> plain
+    thread::spawn(move || {
+        let result = (|| -> io::Result<()> {
+            let mut buffer = [0u8; 16 * 1024];
+            let mut chunk = [0u8; 16 * 1024];
+
+            loop {
+                let count = encrypted_reader.read(&mut buffer)?;
+
+                if count == 0 {
+                    return Ok(());
+                }
+
+                let mut plaintext = Vec::new();
+
+                let processed = {
+                    let mut session = incoming_session.lock().unwrap();
+                    let mut received = &buffer[..count];
+
+                    let mut processed = Ok(());
+
+                    while processed.is_ok() && !received.is_empty() {
+                        session.read_tls(&mut received)?;
+
+                        processed = session.process_new_packets().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+                    }
+
+                    if processed.is_ok() {
+                        loop {
+                            let read = session.read(&mut chunk)?;
+
+                            if read == 0 {
+                                break;
+                            }
+
+                            plaintext.extend_from_slice(&chunk[..read]);
+                        }
+                    }
+
+                    //On failure this is the alert letting the other side know why
+                    let _ = incoming_records.send(Some(take_tls(&mut *session)?));
+
+                    processed
+                };
+
+                processed?;
+
+                plain_writer.write_all(&plaintext)?;
+            }
+        })();
+
+        if let Err(e) = result {
+            on_error(peer, e.to_string());
+        }
+
+        let _ = incoming_records.send(None);
+        let _ = plain_writer.shutdown(Shutdown::Both);
+
+        on_close();
+    });
+
+    //Plain -> session
+    thread::spawn(move || {
+        let mut buffer = [0u8; 16 * 1024];
+
+        loop {
+            let count = match plain_reader.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => count
+            };
+
+            let mut session = session.lock().unwrap();
+
+            match session.write_all(&buffer[..count]).and_then(|_| take_tls(&mut *session)) {
+                Ok(encrypted) => { let _ = records.send(Some(encrypted)); },
+                Err(_) => break
+            }
+        }
+
+        let _ = records.send(None);
+        let _ = plain_reader.shutdown(Shutdown::Both);
+    });
+}
@@ -1,5 +1,5 @@
 
-use crate::messages::{UiEvents, HostEvent, ParticipantStatus, Severity, NodeType};
+use crate::messages::{UiEvents, HostEvent, ParticipantStatus, Severity, NodeType, Worker};
 use std::time::Duration;
 
 use crossbeam_channel::{Sender, Receiver};
@@ -10,13 +10,14 @@ use crossterm::event::{read, Event, poll};
 use std::io;
 use tui::Terminal;
 use tui::backend::CrosstermBackend;
-use tui::widgets::{Block, Borders, ListItem, List, Row, Table, Cell, ListState, Paragraph};
-use tui::layout::{Layout, Constraint, Direction};
+use tui::widgets::{Block, Borders, ListItem, List, Row, Table, Cell, ListState, Paragraph, Clear};
+use tui::layout::{Layout, Constraint, Direction, Rect};
 use tui::style::{Color, Style};
 use tui::text::{Spans, Span};
 
 
 use std::io::Stdout;
+use std::collections::HashMap;
 use bimap::BiMap;
 use message_io::network::Endpoint;
 
@@ -25,20 +26,116 @@ use tui::text::Text;
 
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct ParticipantInfo {
-    endpoint: Endpoint,
+    worker: Worker,
     status: ParticipantStatus,
     progress: Option<i32>,
+    excluded: bool,
 }
 
 impl ParticipantInfo {
-    fn new(endpoint: Endpoint) -> Self {
+    fn new(worker: Worker) -> Self {
 
         ParticipantInfo {
-            endpoint,
+            worker,
             status: ParticipantStatus::Idle,
             progress: None,
+            excluded: false,
         }
     }
+
+    // Progress is kept in hundredths of a percent
+    fn percent(& self) -> Option<f32> {
+        self.progress.map(|hundredths| hundredths as f32 / 100.0f32)
+    }
+}
+
+// A participant process, whose threads are shown beneath it in the participants tree
+struct MachineInfo {
+    name: String,
+    expanded: bool,
+}
+
+// Row of the participants tree, either a participant process or one of its threads
+#[derive(PartialEq, Eq, Clone)]
+enum TreeItem {
+    Machine(Endpoint),
+    Worker(String),
+}
+
+// Combined state of the threads of a participant process
+struct MachineSummary {
+    threads: usize,
+    busy: usize,
+    excluded: usize,
+    unresponsive: bool,
+
+    //Mean progress of the busy threads, in percent
+    progress: Option<f32>,
+}
+
+impl MachineSummary {
+    fn color(& self) -> Color {
+        if self.unresponsive {
+            ParticipantStatus::Unresponsive.to_color()
+        } else if self.threads > 0 && self.excluded == self.threads {
+            Color::DarkGray
+        } else if self.busy > 0 {
+            ParticipantStatus::Calculating.to_color()
+        } else {
+            ParticipantStatus::Idle.to_color()
+        }
+    }
+}
+
+// Actions that can be taken on the selected participant, from the context menu or their shortcut.
+// On a participant process they act on all of its threads
+#[derive(Clone, Copy)]
+enum ParticipantAction {
+    Pause,
+    Play,
+    Kill,
+    Kick,
+    Exclude,
+}
+
+const PARTICIPANT_ACTIONS: [ParticipantAction; 5] = [
+    ParticipantAction::Pause,
+    ParticipantAction::Play,
+    ParticipantAction::Kill,
+    ParticipantAction::Kick,
+    ParticipantAction::Exclude,
+];
+
+impl ParticipantAction {
+    fn label(& self, excluded: bool) -> &'static str {
+        match self {
+            ParticipantAction::Pause => "Pause             P",
+            ParticipantAction::Play => "Play              L",
+            ParticipantAction::Kill => "Kill              K",
+            ParticipantAction::Kick => "Kick              R",
+            ParticipantAction::Exclude => if excluded {
+                "Include in jobs   X"
+            } else {
+                "Exclude from jobs X"
+            },
+        }
+    }
+}
+
+// Progress as shown in the participants tree and the details pane
+fn format_progress(progress: Option<f32>) -> String {
+    match progress {
+        Some(progress) => format!("{:.1}%", progress),
+        None => String::from("-")
+    }
+}
+
+// Rectangle of the given size in the middle of `area`, used for popups
+fn centered_rect(width: u16, height: u16, area: Rect) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
 }
 
 struct LogEntry {
@@ -82,14 +179,18 @@ pub struct Panel<'a> {
 
     participants: BiMap<String, ParticipantInfo>,
 
-    participant_names: Vec<String>,
+    machines: HashMap<Endpoint, MachineInfo>,
+
+    tree_items: Vec<TreeItem>,
 
-    selected_participant: Option<String>,
+    selected_item: Option<TreeItem>,
 
     participants_state: ListState,
 
     top_scroll_log_item: usize, //Index of the log item to show at the top
 
+    action_menu_state: Option<ListState>, //Context menu for the selected participant, if open
+
 
 
     logs: Vec<LogEntry>,
@@ -111,10 +212,12 @@ impl<'a> Panel<'a> {
             terminal,
             script_path,
             participants: BiMap::new(),
+            machines: HashMap::new(),
             participants_state: ListState::default(),
-            selected_participant: None,
-            participant_names: Vec::new(),
+            selected_item: None,
+            tree_items: Vec::new(),
             top_scroll_log_item: 0,
+            action_menu_state: None,
             logs: Vec::new()
         }
     }
@@ -123,23 +226,206 @@ impl<'a> Panel<'a> {
         let mut is_calculating = false;
 
         for info in self.participants.right_values().into_iter() {
-            is_calculating |= info.status != ParticipantStatus::Idle;
+            is_calculating |= info.status == ParticipantStatus::Calculating || info.status == ParticipantStatus::Paused;
         }
 
         is_calculating
     }
 
+    // Threads of the participant process at `endpoint`, sorted by name
+    fn workers_of(& self, endpoint: Endpoint) -> Vec<(& String, & ParticipantInfo)> {
+        let mut workers: Vec<(& String, & ParticipantInfo)> = self.participants.iter()
+            .filter(|(_, info)| info.worker.endpoint == endpoint)
+            .collect();
+
+        workers.sort_by(|a, b| a.0.cmp(b.0));
+
+        workers
+    }
+
+    fn summarise(& self, endpoint: Endpoint) -> MachineSummary {
+        let workers = self.workers_of(endpoint);
+
+        let busy: Vec<& ParticipantInfo> = workers.iter()
+            .map(|(_, info)| *info)
+            .filter(|info| info.status == ParticipantStatus::Calculating || info.status == ParticipantStatus::Paused)
+            .collect();
+
+        let progress = if busy.is_empty() {
+            None
+        } else {
+            Some(busy.iter().map(|info| info.percent().unwrap_or(0.0)).sum::<f32>() / busy.len() as f32)
+        };
+
+        MachineSummary {
+            threads: workers.len(),
+            busy: busy.len(),
+            excluded: workers.iter().filter(|(_, info)| info.excluded).count(),
+            unresponsive: workers.iter().any(|(_, info)| info.status == ParticipantStatus::Unresponsive),
+            progress,
+        }
+    }
+
+    // Rows of the participants tree. Participant processes are sorted by name, with their threads
+    // beneath them if they are expanded
+    fn build_tree(& self) -> Vec<TreeItem> {
+        let mut machines: Vec<(& Endpoint, & MachineInfo)> = self.machines.iter().collect();
+
+        machines.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+
+        let mut items = Vec::new();
+
+        for (endpoint, machine) in machines {
+            items.push(TreeItem::Machine(*endpoint));
+
+            if machine.expanded {
+                for (name, _) in self.workers_of(*endpoint) {
+                    items.push(TreeItem::Worker(name.clone()));
+                }
+            }
+        }
+
+        items
+    }
+
+    // Name shown for a row of the tree, and the threads that actions on it apply to
+    fn item_name(& self, item: & TreeItem) -> Option<String> {
+        match item {
+            TreeItem::Machine(endpoint) => self.machines.get(endpoint).map(|machine| machine.name.clone()),
+            TreeItem::Worker(name) => Some(name.clone()),
+        }
+    }
+
+    fn item_workers(& self, item: & TreeItem) -> Vec<String> {
+        match item {
+            TreeItem::Machine(endpoint) => self.workers_of(*endpoint).into_iter().map(|(name, _)| name.clone()).collect(),
+            TreeItem::Worker(name) => vec![name.clone()],
+        }
+    }
+
+    // Whether every thread of the item is excluded from jobs
+    fn item_excluded(& self, item: & TreeItem) -> bool {
+        let workers = self.item_workers(item);
+
+        !workers.is_empty() && workers.iter().all(|name| self.participants.get_by_left(name).map_or(false, |info| info.excluded))
+    }
+
+    fn act_on_selected(& mut self, action: ParticipantAction) {
+        let item = match &self.selected_item {
+            Some(item) => item.clone(),
+            None => return
+        };
+
+        let name = match self.item_name(&item) {
+            Some(name) => name,
+            None => return
+        };
+
+        let workers: Vec<ParticipantInfo> = self.item_workers(&item).iter()
+            .filter_map(|name| self.participants.get_by_left(name).cloned())
+            .collect();
+
+        let endpoint = match (&item, workers.first()) {
+            (TreeItem::Machine(endpoint), _) => *endpoint,
+            (TreeItem::Worker(_), Some(info)) => info.worker.endpoint,
+            (TreeItem::Worker(_), None) => return
+        };
+
+        match action {
+            ParticipantAction::Pause => {
+                for info in workers.iter() {
+                    self.command_sender.send(HostEvent::Pause(info.worker)).unwrap();
+                }
+            },
+            ParticipantAction::Play => {
+                for info in workers.iter() {
+                    self.command_sender.send(HostEvent::Play(info.worker)).unwrap();
+                }
+            },
+            ParticipantAction::Kill => {
+                for info in workers.iter() {
+                    self.command_sender.send(HostEvent::Kill(info.worker)).unwrap();
+                }
+                self.logs.insert(0, LogEntry::new(Severity::Info, NodeType::Participant(name.clone()), format!("Terminating participant.")));
+            },
+            ParticipantAction::Kick => {
+                self.command_sender.send(HostEvent::Remove(endpoint)).unwrap();
+                self.logs.insert(0, LogEntry::new(Severity::Info, NodeType::Participant(name.clone()), format!("Removing participant.")));
+            },
+            ParticipantAction::Exclude => {
+                //A participant process with only some threads excluded has the rest excluded too
+                let excluded = !self.item_excluded(&item);
+
+                for info in workers.iter() {
+                    let (worker_name, mut info) = self.participants.remove_by_right(info).unwrap();
+                    info.excluded = excluded;
+                    self.command_sender.send(HostEvent::Exclude(info.worker, excluded)).unwrap();
+                    self.participants.insert(worker_name, info);
+                }
+
+                self.logs.insert(0, LogEntry::new(Severity::Info, NodeType::Participant(name.clone()), if excluded {
+                    format!("Participant excluded from jobs.")
+                } else {
+                    format!("Participant included in jobs.")
+                }));
+            },
+        }
+    }
+
+    // Shows or hides the threads of the selected participant process. Collapsing a thread's row
+    // collapses its participant process and selects it instead
+    fn expand_selected(& mut self, expanded: bool) {
+        let endpoint = match &self.selected_item {
+            Some(TreeItem::Machine(endpoint)) => *endpoint,
+            Some(TreeItem::Worker(name)) if !expanded => match self.participants.get_by_left(name) {
+                Some(info) => info.worker.endpoint,
+                None => return
+            },
+            _ => return
+        };
+
+        if let Some(machine) = self.machines.get_mut(&endpoint) {
+            machine.expanded = expanded;
+        }
+
+        if let Some(index) = self.tree_items.iter().position(|item| *item == TreeItem::Machine(endpoint)) {
+            self.participants_state.select(Some(index));
+        }
+    }
+
     pub fn tick(& mut self) -> Result<(), ()> {
 
         //If no participant is selected, try and select one
-        if !self.participant_names.is_empty() && self.selected_participant == Option::None {
-            self.selected_participant = Some(self.participant_names.get(0).unwrap().clone());
+        if !self.tree_items.is_empty() && self.selected_item == Option::None {
+            self.selected_item = Some(self.tree_items.get(0).unwrap().clone());
             self.participants_state.select(Some(0));
         }
 
         //When a button is clicked or an action is invoked, we must send the event via the ui_sender
         if let Ok(true) = poll(Duration::from_secs(0)) {
             match read().unwrap() {
+                //While the context menu is open, keys navigate the menu
+                Event::Key(key_event) if self.action_menu_state.is_some() => {
+                    let menu_state = self.action_menu_state.as_mut().unwrap();
+                    let selected_index = menu_state.selected().unwrap();
+
+                    match key_event.code {
+                        crossterm::event::KeyCode::Up => {
+                            menu_state.select(Some((selected_index + PARTICIPANT_ACTIONS.len() - 1) % PARTICIPANT_ACTIONS.len()));
+                        },
+                        crossterm::event::KeyCode::Down => {
+                            menu_state.select(Some((selected_index + 1) % PARTICIPANT_ACTIONS.len()));
+                        },
+                        crossterm::event::KeyCode::Enter => {
+                            self.action_menu_state = None;
+                            self.act_on_selected(PARTICIPANT_ACTIONS[selected_index]);
+                        },
+                        crossterm::event::KeyCode::Esc => {
+                            self.action_menu_state = None;
+                        },
+                        _ => {}
+                    }
+                }
                 Event::Key(key_event) => {
                     match key_event.code {
                         crossterm::event::KeyCode::Char('e') => {
@@ -156,6 +442,9 @@ impl<'a> Panel<'a> {
                         crossterm::event::KeyCode::Char('l') => {
                             self.command_sender.send(HostEvent::PlayAll).unwrap();
                         },
+                        crossterm::event::KeyCode::Char('s') => {
+                            self.command_sender.send(HostEvent::StopAll).unwrap();
+                        },
                         crossterm::event::KeyCode::Char('k') => {
                             self.command_sender.send(HostEvent::KillAll).unwrap();
                             self.logs.insert(0, LogEntry::new(Severity::Info, NodeType::Host, format!("Terminating all participants.")));
@@ -168,32 +457,53 @@ impl<'a> Panel<'a> {
                             self.terminal.clear().unwrap();
                             return Err(());
                         },
+                        crossterm::event::KeyCode::Char('P') => {
+                            self.act_on_selected(ParticipantAction::Pause);
+                        },
+                        crossterm::event::KeyCode::Char('L') => {
+                            self.act_on_selected(ParticipantAction::Play);
+                        },
+                        crossterm::event::KeyCode::Char('K') => {
+                            self.act_on_selected(ParticipantAction::Kill);
+                        },
+                        crossterm::event::KeyCode::Char('R') => {
+                            self.act_on_selected(ParticipantAction::Kick);
+                        },
+                        crossterm::event::KeyCode::Char('X') => {
+                            self.act_on_selected(ParticipantAction::Exclude);
+                        },
+                        crossterm::event::KeyCode::Enter => {
+                            if self.selected_item.is_some() {
+                                let mut menu_state = ListState::default();
+                                menu_state.select(Some(0));
+                                self.action_menu_state = Some(menu_state);
+                            }
+                        },
                         crossterm::event::KeyCode::Char('c') => {
                             self.logs.clear();
                             self.top_scroll_log_item = 0;
                         },
                         crossterm::event::KeyCode::Left => {
-
-
+                            self.expand_selected(false);
                         },
                         crossterm::event::KeyCode::Right => {
-
+                            self.expand_selected(true);
                         },
                         crossterm::event::KeyCode::Up => {
 
-                            if !self.participants.is_empty() {
+                            if !self.tree_items.is_empty() {
 
                                 let selected_index = self.participants_state.selected().unwrap();
 
                                 if selected_index != 0 {
                                     self.participants_state.select(Some(selected_index - 1));
                                 } else {
-                                    self.participants_state.select(Some(self.participant_names.len() - 1));
+                                    self.participants_state.select(Some(self.tree_items.len() - 1));
                                 }
                             }
                         },
                         crossterm::event::KeyCode::Down => {
-                            if !self.participants.is_empty() {
+                            if !self.tree_items.is_empty() {
                                 let selected_index = self.participants_state.selected().unwrap();
 
                                 self.participants_state.select(Some(selected_index + 1));
@@ -222,9 +532,9 @@ impl<'a> Panel<'a> {
             }
         }
 
-        self.selected_participant = match self.participants_state.selected() {
-            Some(index) => match self.participant_names.get(index) {
-                Some(name) => Some(name.clone()),
+        self.selected_item = match self.participants_state.selected() {
+            Some(index) => match self.tree_items.get(index) {
+                Some(item) => Some(item.clone()),
                 None => None
             }
             None => None
@@ -233,7 +543,7 @@ impl<'a> Panel<'a> {
         //We must also check ui_event_queue and see if we need to change the UI
         match self.message_receiver.recv_timeout(Duration::from_micros(0)) {
             Ok(event) => match event {
-                UiEvents::ChangeStatusTo(status, _endpoint, name) => {
+                UiEvents::ChangeStatusTo(status, _worker, name) => {
                     let (_, mut info) = self.participants.remove_by_left(&name).unwrap();
                     if let ParticipantStatus::Idle = status {
                         info.progress = None;
@@ -247,8 +557,18 @@ impl<'a> Panel<'a> {
                     self.logs.insert(0, LogEntry::new(severity, node_type, message));
                 }
 
-                UiEvents::ParticipantRegistered(endpoint, name) => {
-                    self.participants.insert(name, ParticipantInfo::new(endpoint));
+                UiEvents::ParticipantRegistered(worker, name) => {
+                    self.participants.insert(name, ParticipantInfo::new(worker));
+                },
+                UiEvents::MachineRegistered(endpoint, name) => {
+                    //Collapsed at first, so that machines with many threads do not fill the list
+                    self.machines.insert(endpoint, MachineInfo {
+                        name,
+                        expanded: false,
+                    });
+                },
+                UiEvents::MachineUnregistered(endpoint) => {
+                    self.machines.remove(&endpoint);
                 },
                 UiEvents::ParticipantUnregistered( name) => {
 
@@ -258,6 +578,9 @@ impl<'a> Panel<'a> {
                 UiEvents::InterpretResultsReturn(return_message) => {
                     self.logs.insert(0, LogEntry::new(Severity::Result, NodeType::Host, return_message));
                 },
+                UiEvents::JobFailed(message) => {
+                    self.logs.insert(0, LogEntry::new(Severity::Error, NodeType::Host, message));
+                },
                 UiEvents::ParticipantProgress(name, progress) => {
                     let (_, mut info) = self.participants.remove_by_left(&name).unwrap();
                     info.progress = Some((progress * 100.0f32) as i32);
@@ -270,41 +593,91 @@ impl<'a> Panel<'a> {
         }
 
 
-        self.participant_names = self.participants.iter()
-            .map(|(string, _)| string.clone())
-            .collect()
-            ;
+        self.tree_items = self.build_tree();
+
+        //Rows move as participants come and go or are expanded, so follow the selected one
+        if let Some(item) = &self.selected_item {
+            match self.tree_items.iter().position(|other| other == item) {
+                Some(index) => self.participants_state.select(Some(index)),
+                None => {
+                    self.selected_item = None;
+                    self.participants_state.select(None);
+                }
+            }
+        }
+
+        let participant_items: Vec<_> = self.tree_items.iter()
+            .filter_map(|item| match item {
+                TreeItem::Machine(endpoint) => {
+                    let machine = self.machines.get(endpoint)?;
+                    let summary = self.summarise(*endpoint);
+
+                    let progress = match summary.progress {
+                        Some(_) => format!(" {}", format_progress(summary.progress)),
+                        None => String::new()
+                    };
+
+                    Some(ListItem::new(format!("{} {} {}/{}{}", if machine.expanded { "▾" } else { "▸" }, machine.name, summary.busy, summary.threads, progress))
+                        .style(Style::default().fg(summary.color())))
+                },
+                TreeItem::Worker(name) => {
+                    let info = self.participants.get_by_left(name)?;
 
-        self.participant_names.sort();
+                    //Excluded participants are dimmed so they stand out from the rest
+                    let color = if info.excluded { Color::DarkGray } else { info.status.to_color() };
 
-        let participant_items: Vec<_> = self.participant_names.iter()
-            .map(|string| ListItem::new(string.as_str())
-                .style(Style::default()
-                    .fg(
-                        self.participants.get_by_left(string).unwrap().status.to_color()
-                    )))
+                    Some(ListItem::new(format!("    {}", name))
+                        .style(Style::default().fg(color)))
+                },
+            })
             .collect();
 
-        let state = & mut self.participants_state;
-        //let state = & mut self.participants_state;
+        let action_items: Vec<_> = match &self.selected_item {
+            Some(item) if !self.item_workers(item).is_empty() => {
+                let excluded = self.item_excluded(item);
+
+                PARTICIPANT_ACTIONS.iter()
+                    .map(|action| ListItem::new(action.label(excluded)))
+                    .collect()
+            },
+            _ => Vec::new()
+        };
+
+        //Close the menu if the participant it was opened for has gone
+        if action_items.is_empty() {
+            self.action_menu_state = None;
+        }
+
+        let text = match &self.selected_item {
+            Some(TreeItem::Machine(endpoint)) => {
+                match self.machines.get(endpoint) {
+                    Some(machine) => {
+                        let summary = self.summarise(*endpoint);
 
-        let text = match &self.selected_participant {
-            Some(name) => {
+                        Text::from(vec![
+                            Spans::from(format!("Name:     {}", machine.name)),
+                            Spans::from(format!("Endpoint: {}", endpoint)),
+                            Spans::from(format!("Busy:     {} of {} threads", summary.busy, summary.threads)),
+                            Spans::from(format!("Progress: {}", format_progress(summary.progress))),
+                            Spans::from(format!("Excluded: {} of {} threads", summary.excluded, summary.threads)),
+                        ])
+                    }
+                    None => Text::raw("")
+                }
+            }
+            Some(TreeItem::Worker(name)) => {
                 match self.participants.get_by_left(name) {
                     Some(info) => {
                         Text::from(vec![
                             Spans::from(format!("Name:     {}", name)),
-                            Spans::from(format!("Endpoint: {}", info.endpoint)),
+                            Spans::from(format!("Endpoint: {}", info.worker.endpoint)),
+                            Spans::from(format!("Slot:     {}", info.worker.slot)),
                             Spans::from(vec![/*format!("Progress: {:?}", info.progress)*/
                                              Span::raw("Status:   "),
                                              Span::styled(format!("{:?}", info.status), Style::default().fg(info.status.to_color()))
                             ]),
-                            Spans::from(format!("Progress: {}",
-                                                match info.progress {
-                                                    Some(number) => format!("{}%", number as f32 / 100.0f32),
-                                                    None => format!("-")
-                                                }
-                            )),
+                            Spans::from(format!("Progress: {}", format_progress(info.percent()))),
+                            Spans::from(format!("Excluded: {}", if info.excluded { "Yes" } else { "No" })),
                         ])
                     }
                     None => Text::raw("")
@@ -315,6 +688,10 @@ impl<'a> Panel<'a> {
             }
         };
 
+        let state = & mut self.participants_state;
+        //let state = & mut self.participants_state;
+        let menu_state = & mut self.action_menu_state;
+
 
         let top_scroll = self.top_scroll_log_item;
 
@@ -390,8 +767,14 @@ impl<'a> Panel<'a> {
                 Span::styled("Pause            ", Style::default().fg(Color::Rgb(58, 47, 77))),
                 Span::raw("l "),
                 Span::styled("Play             ", Style::default().fg(Color::Rgb(58, 47, 77))),
+                Span::raw("s "),
+                Span::styled("Stop             ", Style::default().fg(Color::Rgb(58, 47, 77))),
                 Span::raw("k "),
                 Span::styled("Kill             ", Style::default().fg(Color::Rgb(58, 47, 77))),
+                Span::raw("Enter "),
+                Span::styled("Participant menu ", Style::default().fg(Color::Rgb(58, 47, 77))),
+                Span::raw("←/→ "),
+                Span::styled("Fold threads     ", Style::default().fg(Color::Rgb(58, 47, 77))),
                 Span::raw("c "),
                 Span::styled("Clear Log        ", Style::default().fg(Color::Rgb(58, 47, 77))),
                 Span::raw("PgDn "),
@@ -402,6 +785,19 @@ impl<'a> Panel<'a> {
 
             f.render_widget(shortcuts, v_chunks[2]);
 
+            if let Some(menu_state) = menu_state {
+                let area = centered_rect(24, PARTICIPANT_ACTIONS.len() as u16 + 2, f.size());
+
+                let action_menu = List::new(action_items)
+                    .block(Block::default().title("Participant").borders(Borders::ALL))
+                    .style(Style::default().fg(Color::White))
+                    .highlight_style(Style::default()
+                        .bg(Color::Rgb(50, 50, 50)));
+
+                f.render_widget(Clear, area);
+                f.render_stateful_widget(action_menu, area, menu_state);
+            }
+
         }).unwrap();
         self.terminal.autoresize().unwrap();
 